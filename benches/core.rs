@@ -0,0 +1,111 @@
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use rs_chip_8::asm;
+use rs_chip_8::chip8::recompiler::Recompiler;
+use rs_chip_8::chip8::{Chip8, KeyBoard};
+
+// Small loops that each spend most of their time in one kind of instruction
+const PROGRAMS: [(&str, &str); 4] = [
+    (
+        "alu",
+        "
+        loop:   ADD V0, 1
+                LD V1, V0
+                XOR V1, V2
+                ADD V2, V1
+                SHR V3, V1
+                SUB V4, V3
+                JP loop
+        ",
+    ),
+    (
+        "branch",
+        "
+        loop:   CALL sub
+                SE V0, 0xff
+                JP loop
+                SNE V1, V0
+                LD V0, 0
+                JP loop
+        sub:    ADD V0, 1
+                RET
+        ",
+    ),
+    (
+        "draw",
+        "
+                LD I, sprite
+        loop:   DRW V0, V1, 8
+                ADD V0, 3
+                ADD V1, 1
+                JP loop
+        sprite: DB 0xff, 0x81, 0xbd, 0xa5, 0xa5, 0xbd, 0x81, 0xff
+        ",
+    ),
+    (
+        "memory",
+        "
+        loop:   LD I, buffer
+                LD B, V0
+                LD [I], V7
+                LD V7, [I]
+                ADD V0, 1
+                JP loop
+        buffer: DB 0, 0, 0, 0, 0, 0, 0, 0
+        ",
+    ),
+];
+
+const INSTRUCTIONS: usize = 1000;
+
+fn machine(src: &str) -> Chip8 {
+    let rom = asm::assemble(src, 0x200).unwrap().bytes;
+    let mut chip8 = Chip8::new();
+    chip8.set_seed(0);
+    chip8.load_rom(&rom).unwrap();
+    chip8
+}
+
+fn step(c: &mut Criterion) {
+    let kb = KeyBoard::new();
+    let mut group = c.benchmark_group("step");
+    for (name, src) in PROGRAMS {
+        // The programs loop forever, so one machine keeps running across iterations
+        let mut chip8 = machine(src);
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                for _ in 0..INSTRUCTIONS {
+                    chip8.step(&kb).unwrap();
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+fn recompiled(c: &mut Criterion) {
+    let kb = KeyBoard::new();
+    let mut group = c.benchmark_group("recompiled");
+    for (name, src) in PROGRAMS {
+        let mut chip8 = machine(src);
+        let mut recompiler = Recompiler::new();
+        group.bench_function(name, |b| {
+            b.iter(|| recompiler.run(&mut chip8, &kb, INSTRUCTIONS).unwrap())
+        });
+    }
+    group.finish();
+}
+
+fn run_frame(c: &mut Criterion) {
+    let kb = KeyBoard::new();
+    let chip8 = machine(PROGRAMS[0].1);
+    c.bench_function("run_frame", |b| {
+        b.iter_batched_ref(
+            || chip8.clone(),
+            |chip8| chip8.run_frame(&kb, INSTRUCTIONS).unwrap(),
+            BatchSize::SmallInput,
+        )
+    });
+}
+
+criterion_group!(benches, step, recompiled, run_frame);
+criterion_main!(benches);
@@ -0,0 +1,9 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use rs_chip_8::stress;
+
+fuzz_target!(|data: &[u8]| {
+    let (quirks, data) = stress::split_quirks(data);
+    let _ = stress::run_opcodes(data, quirks);
+});
@@ -0,0 +1,12 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use rs_chip_8::chip8::MemoryPolicy;
+use rs_chip_8::stress;
+
+fuzz_target!(|data: &[u8]| {
+    let (quirks, data) = stress::split_quirks(data);
+    for policy in [MemoryPolicy::Wrap, MemoryPolicy::Error, MemoryPolicy::Trap] {
+        let _ = stress::run_rom(data, 10_000, policy, quirks);
+    }
+});
@@ -0,0 +1,273 @@
+use std::collections::BTreeMap;
+
+// Assembles the mnemonics printed by the disassembler.
+//
+//     loop:   LD V0, K        ; comments start with ';'
+//             SE V0, 0x0a
+//             JP loop
+//     data:   DB 0xf0, 0x90, 0b11110000
+//             DW 0x1234
+pub struct Program {
+    pub bytes: Vec<u8>,
+    pub labels: BTreeMap<String, u16>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Operand {
+    V(u16),
+    I,
+    IndirectI,
+    Dt,
+    St,
+    K,
+    F,
+    B,
+    Value(u16),
+}
+
+struct Statement<'a> {
+    line: usize,
+    mnemonic: String,
+    operands: Vec<&'a str>,
+}
+
+pub fn assemble(src: &str, origin: u16) -> Result<Program, String> {
+    // Pass 1: find the address of every label
+    let mut labels = BTreeMap::new();
+    let mut statements = Vec::new();
+    let mut addr = origin as usize;
+    for (n, line) in src.lines().enumerate() {
+        let line_no = n + 1;
+        let mut rest = line.split(';').next().unwrap().trim();
+        while let Some(colon) = rest.find(':') {
+            let label = rest[..colon].trim();
+            if !is_label(label) {
+                return Err(format!("line {}: invalid label: {:?}", line_no, label));
+            }
+            if labels.insert(label.to_string(), addr as u16).is_some() {
+                return Err(format!("line {}: duplicate label: {}", line_no, label));
+            }
+            rest = rest[colon + 1..].trim();
+        }
+        if rest.is_empty() {
+            continue;
+        }
+        let (mnemonic, args) = match rest.find(char::is_whitespace) {
+            Some(k) => (&rest[..k], rest[k..].trim()),
+            None => (rest, ""),
+        };
+        let operands: Vec<&str> = if args.is_empty() {
+            Vec::new()
+        } else {
+            args.split(',').map(|a| a.trim()).collect()
+        };
+        let mnemonic = mnemonic.to_uppercase();
+        addr += match mnemonic.as_str() {
+            "DB" => operands.len(),
+            "DW" => operands.len() * 2,
+            _ => 2,
+        };
+        if addr > 0x1000 {
+            return Err(format!("line {}: program does not fit in memory", line_no));
+        }
+        statements.push(Statement {
+            line: line_no,
+            mnemonic,
+            operands,
+        });
+    }
+
+    // Pass 2: encode
+    let mut bytes = Vec::new();
+    for st in &statements {
+        let operands = st
+            .operands
+            .iter()
+            .map(|o| operand(o, &labels))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| format!("line {}: {}", st.line, e))?;
+        match st.mnemonic.as_str() {
+            "DB" => {
+                for o in &operands {
+                    bytes.push(
+                        value(*o, 0xff).map_err(|e| format!("line {}: {}", st.line, e))? as u8,
+                    );
+                }
+            }
+            "DW" => {
+                for o in &operands {
+                    let w = value(*o, 0xffff).map_err(|e| format!("line {}: {}", st.line, e))?;
+                    bytes.extend_from_slice(&w.to_be_bytes());
+                }
+            }
+            _ => {
+                let opcode = encode(&st.mnemonic, &operands)
+                    .map_err(|e| format!("line {}: {}", st.line, e))?;
+                bytes.extend_from_slice(&opcode.to_be_bytes());
+            }
+        }
+    }
+    Ok(Program { bytes, labels })
+}
+
+fn encode(mnemonic: &str, operands: &[Operand]) -> Result<u16, String> {
+    use Operand::*;
+    let addr = |o: Operand| value(o, 0xfff);
+    let byte = |o: Operand| value(o, 0xff);
+    let opcode = match (mnemonic, operands) {
+        ("CLS", []) => 0x00e0,
+        ("RET", []) => 0x00ee,
+        ("SYS", [a]) => addr(*a)?,
+        ("JP", [V(0), a]) => 0xb000 | addr(*a)?,
+        ("JP", [a]) => 0x1000 | addr(*a)?,
+        ("CALL", [a]) => 0x2000 | addr(*a)?,
+        ("SE", [V(x), V(y)]) => 0x5000 | x << 8 | y << 4,
+        ("SE", [V(x), b]) => 0x3000 | x << 8 | byte(*b)?,
+        ("SNE", [V(x), V(y)]) => 0x9000 | x << 8 | y << 4,
+        ("SNE", [V(x), b]) => 0x4000 | x << 8 | byte(*b)?,
+        ("LD", [V(x), V(y)]) => 0x8000 | x << 8 | y << 4,
+        ("LD", [I, a]) => 0xa000 | addr(*a)?,
+        ("LD", [V(x), Dt]) => 0xf007 | x << 8,
+        ("LD", [V(x), K]) => 0xf00a | x << 8,
+        ("LD", [Dt, V(x)]) => 0xf015 | x << 8,
+        ("LD", [St, V(x)]) => 0xf018 | x << 8,
+        ("LD", [F, V(x)]) => 0xf029 | x << 8,
+        ("LD", [B, V(x)]) => 0xf033 | x << 8,
+        ("LD", [IndirectI, V(x)]) => 0xf055 | x << 8,
+        ("LD", [V(x), IndirectI]) => 0xf065 | x << 8,
+        ("LD", [V(x), b]) => 0x6000 | x << 8 | byte(*b)?,
+        ("ADD", [I, V(x)]) => 0xf01e | x << 8,
+        ("ADD", [V(x), V(y)]) => 0x8004 | x << 8 | y << 4,
+        ("ADD", [V(x), b]) => 0x7000 | x << 8 | byte(*b)?,
+        ("OR", [V(x), V(y)]) => 0x8001 | x << 8 | y << 4,
+        ("AND", [V(x), V(y)]) => 0x8002 | x << 8 | y << 4,
+        ("XOR", [V(x), V(y)]) => 0x8003 | x << 8 | y << 4,
+        ("SUB", [V(x), V(y)]) => 0x8005 | x << 8 | y << 4,
+        ("SHR", [V(x), V(y)]) => 0x8006 | x << 8 | y << 4,
+        ("SHR", [V(x)]) => 0x8006 | x << 8 | x << 4,
+        ("SUBN", [V(x), V(y)]) => 0x8007 | x << 8 | y << 4,
+        ("SHL", [V(x), V(y)]) => 0x800e | x << 8 | y << 4,
+        ("SHL", [V(x)]) => 0x800e | x << 8 | x << 4,
+        ("RND", [V(x), b]) => 0xc000 | x << 8 | byte(*b)?,
+        ("DRW", [V(x), V(y), n]) => 0xd000 | x << 8 | y << 4 | value(*n, 0xf)?,
+        ("SKP", [V(x)]) => 0xe09e | x << 8,
+        ("SKNP", [V(x)]) => 0xe0a1 | x << 8,
+        _ => return Err(format!("invalid instruction: {}", mnemonic)),
+    };
+    Ok(opcode)
+}
+
+fn operand(s: &str, labels: &BTreeMap<String, u16>) -> Result<Operand, String> {
+    let upper = s.to_uppercase();
+    let o = match upper.as_str() {
+        "I" => Operand::I,
+        "[I]" => Operand::IndirectI,
+        "DT" => Operand::Dt,
+        "ST" => Operand::St,
+        "K" => Operand::K,
+        "F" => Operand::F,
+        "B" => Operand::B,
+        _ if upper.len() == 2 && upper.starts_with('V') => {
+            match u16::from_str_radix(&upper[1..], 16) {
+                Ok(x) => Operand::V(x),
+                Err(_) => return Err(format!("invalid register: {}", s)),
+            }
+        }
+        _ => Operand::Value(
+            number(s)
+                .or_else(|| labels.get(s).copied())
+                .ok_or_else(|| {
+                    if is_label(s) {
+                        format!("undefined label: {}", s)
+                    } else {
+                        format!("invalid operand: {:?}", s)
+                    }
+                })?,
+        ),
+    };
+    Ok(o)
+}
+
+fn value(o: Operand, max: u16) -> Result<u16, String> {
+    match o {
+        Operand::Value(v) if v <= max => Ok(v),
+        Operand::Value(v) => Err(format!("value out of range: 0x{:x} > 0x{:x}", v, max)),
+        _ => Err("expected a value".to_string()),
+    }
+}
+
+pub fn number(s: &str) -> Option<u16> {
+    if let Some(hex) = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        u16::from_str_radix(hex, 16).ok()
+    } else if let Some(bin) = s.strip_prefix("0b").or_else(|| s.strip_prefix("0B")) {
+        u16::from_str_radix(bin, 2).ok()
+    } else if s.starts_with(|c: char| c.is_ascii_digit()) {
+        s.parse().ok()
+    } else {
+        None
+    }
+}
+
+fn is_label(s: &str) -> bool {
+    let mut chars = s.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disasm;
+
+    #[test]
+    fn labels() {
+        let src = "
+            start:  LD V0, 0        ; counter
+            loop:   ADD V0, 1
+                    SE V0, 10
+                    JP loop
+            end:    JP end
+            sprite: DB 0b11110000, 0x90
+                    DW 0x1234
+        ";
+        let p = assemble(src, 0x200).unwrap();
+        assert_eq!(
+            vec![
+                0x60, 0x00, 0x70, 0x01, 0x30, 0x0a, 0x12, 0x02, 0x12, 0x08, 0xf0, 0x90, 0x12, 0x34
+            ],
+            p.bytes
+        );
+        assert_eq!(Some(&0x202), p.labels.get("loop"));
+        assert_eq!(Some(&0x20a), p.labels.get("sprite"));
+    }
+
+    #[test]
+    fn errors() {
+        assert!(assemble("LD V0, 0x100", 0x200).is_err());
+        assert!(assemble("JP nowhere", 0x200).is_err());
+        assert!(assemble("DRW V0, V1", 0x200).is_err());
+        assert!(assemble("a: CLS\na: CLS", 0x200).is_err());
+        assert_eq!(
+            "line 2: invalid instruction: FOO",
+            assemble("CLS\nFOO V0", 0x200).err().unwrap()
+        );
+    }
+
+    #[test]
+    fn round_trip() {
+        let rom: Vec<u8> = (0..=0xffffu16)
+            .step_by(7)
+            .flat_map(|op| op.to_be_bytes())
+            .chain([0x12])
+            .collect();
+        let src = disasm::listing(&rom, 0x200);
+        // The listing does not fit in memory, so assemble it from address 0
+        let mut bytes = Vec::new();
+        for chunk in src.lines().collect::<Vec<_>>().chunks(1024) {
+            bytes.extend(assemble(&chunk.join("\n"), 0).unwrap().bytes);
+        }
+        assert_eq!(rom, bytes);
+    }
+}
@@ -0,0 +1,109 @@
+use std::time::{Duration, Instant};
+
+use crate::chip8::recompiler::Recompiler;
+use crate::chip8::{Chip8, KeyBoard};
+
+// Opcode classes by their first nibble
+pub const CLASSES: [&str; 16] = [
+    "0NNN", "1NNN", "2NNN", "3XNN", "4XNN", "5XY0", "6XNN", "7XNN", "8XYN", "9XY0", "ANNN", "BNNN",
+    "CXNN", "DXYN", "EXNN", "FXNN",
+];
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Class {
+    pub count: u64,
+    pub time: Duration,
+}
+
+impl Class {
+    pub fn nanos_per_instruction(&self) -> f64 {
+        if self.count == 0 {
+            0.0
+        } else {
+            self.time.as_nanos() as f64 / self.count as f64
+        }
+    }
+}
+
+// Runs `instructions` instructions with `emulate_cycle` as fast as possible,
+// or in frames of a thousand instructions with the recompiler.
+// Idle loops are not stopped, so the whole count always runs.
+pub fn throughput(rom: &[u8], instructions: u64, recompile: bool) -> Result<Duration, String> {
+    const FRAME: u64 = 1000;
+    let mut chip8 = machine(rom)?;
+    let kb = KeyBoard::new();
+    let s = Instant::now();
+    if recompile {
+        let mut recompiler = Recompiler::new();
+        while chip8.cycles() < instructions {
+            let n = (instructions - chip8.cycles()).min(FRAME);
+            recompiler.run_frame(&mut chip8, &kb, n as usize)?;
+        }
+    } else {
+        for _ in 0..instructions {
+            chip8.emulate_cycle(&kb)?;
+        }
+    }
+    Ok(s.elapsed())
+}
+
+// Times every `decode_execute` call and sums the times by opcode class.
+// The cost of reading the clock is measured first and taken off each sample.
+pub fn profile(rom: &[u8], instructions: u64) -> Result<[Class; 16], String> {
+    let mut chip8 = machine(rom)?;
+    let kb = KeyBoard::new();
+    let overhead = clock_overhead();
+    let mut classes = [Class::default(); 16];
+    for _ in 0..instructions {
+        let opcode = chip8.fetch()?;
+        let s = Instant::now();
+        chip8.decode_execute(opcode, &kb)?;
+        let elapsed = s.elapsed();
+        let class = &mut classes[(opcode >> 12) as usize];
+        class.count += 1;
+        class.time += elapsed.saturating_sub(overhead);
+        chip8.update_timers();
+    }
+    Ok(classes)
+}
+
+fn machine(rom: &[u8]) -> Result<Chip8, String> {
+    let mut chip8 = Chip8::new();
+    chip8.set_seed(0);
+    chip8.load_rom(rom).map_err(|e| e.to_string())?;
+    Ok(chip8)
+}
+
+fn clock_overhead() -> Duration {
+    const SAMPLES: u32 = 10_000;
+    let mut total = Duration::ZERO;
+    for _ in 0..SAMPLES {
+        let s = Instant::now();
+        total += s.elapsed();
+    }
+    total / SAMPLES
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm;
+
+    #[test]
+    fn profile_counts() {
+        let src = "
+            loop:   LD V0, 1
+                    ADD V0, 2
+                    ADD V1, 3
+                    JP loop
+        ";
+        let rom = asm::assemble(src, 0x200).unwrap().bytes;
+        let classes = profile(&rom, 400).unwrap();
+        assert_eq!(100, classes[0x6].count);
+        assert_eq!(200, classes[0x7].count);
+        assert_eq!(100, classes[0x1].count);
+        assert_eq!(400, classes.iter().map(|c| c.count).sum::<u64>());
+        assert!(throughput(&rom, 400, false).is_ok());
+        assert!(throughput(&rom, 400, true).is_ok());
+    }
+}
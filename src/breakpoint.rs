@@ -0,0 +1,532 @@
+use std::fmt;
+
+use crate::asm;
+use crate::chip8::{Chip8, Registers, MEMORY_SIZE};
+use crate::instruction::decode;
+
+// Registers that conditions and messages can use
+const REGISTERS: [&str; 21] = [
+    "v0", "v1", "v2", "v3", "v4", "v5", "v6", "v7", "v8", "v9", "va", "vb", "vc", "vd", "ve", "vf",
+    "i", "pc", "sp", "dt", "st",
+];
+
+fn register(r: &Registers, n: usize) -> u16 {
+    match n {
+        0..=15 => r.v[n] as u16,
+        16 => r.i,
+        17 => r.pc,
+        18 => r.sp,
+        19 => r.delay_timer as u16,
+        _ => r.sound_timer as u16,
+    }
+}
+
+fn register_number(name: &str) -> Option<usize> {
+    let name = name.to_ascii_lowercase();
+    REGISTERS.iter().position(|&r| r == name)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operand {
+    Register(usize),
+    Value(u16),
+}
+
+impl Operand {
+    fn value(self, r: &Registers) -> u16 {
+        match self {
+            Operand::Register(n) => register(r, n),
+            Operand::Value(v) => v,
+        }
+    }
+}
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Operand::Register(n) => write!(f, "{}", REGISTERS[*n]),
+            Operand::Value(v) => write!(f, "0x{:x}", v),
+        }
+    }
+}
+
+const COMPARISONS: [&str; 6] = ["==", "!=", "<=", ">=", "<", ">"];
+
+// A condition on the registers such as `V3 == 0x10 && I > 0x300`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Condition {
+    // Left, one of COMPARISONS and right
+    Compare(Operand, &'static str, Operand),
+    And(Box<Condition>, Box<Condition>),
+    Or(Box<Condition>, Box<Condition>),
+}
+
+impl Condition {
+    pub fn parse(s: &str) -> Result<Condition, String> {
+        let tokens = tokenize(s)?;
+        let mut parser = Parser { tokens, pos: 0 };
+        let condition = parser.or()?;
+        match parser.tokens.get(parser.pos) {
+            None => Ok(condition),
+            Some(token) => Err(format!("unexpected {}", token)),
+        }
+    }
+
+    pub fn holds(&self, r: &Registers) -> bool {
+        match self {
+            Condition::Compare(a, op, b) => {
+                let (a, b) = (a.value(r), b.value(r));
+                match *op {
+                    "==" => a == b,
+                    "!=" => a != b,
+                    "<=" => a <= b,
+                    ">=" => a >= b,
+                    "<" => a < b,
+                    _ => a > b,
+                }
+            }
+            Condition::And(a, b) => a.holds(r) && b.holds(r),
+            Condition::Or(a, b) => a.holds(r) || b.holds(r),
+        }
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Condition::Compare(a, op, b) => write!(f, "{} {} {}", a, op, b),
+            Condition::And(a, b) => write!(f, "({} && {})", a, b),
+            Condition::Or(a, b) => write!(f, "({} || {})", a, b),
+        }
+    }
+}
+
+// Words, numbers, comparisons, && and || and parentheses
+fn tokenize(s: &str) -> Result<Vec<String>, String> {
+    let mut tokens = Vec::new();
+    let mut rest = s.trim_start();
+    while let Some(c) = rest.chars().next() {
+        let len = if c.is_ascii_alphanumeric() {
+            rest.find(|c: char| !c.is_ascii_alphanumeric())
+                .unwrap_or(rest.len())
+        } else if c == '(' || c == ')' {
+            1
+        } else {
+            ["&&", "||"]
+                .iter()
+                .chain(&COMPARISONS)
+                .find(|op| rest.starts_with(*op))
+                .ok_or_else(|| format!("unexpected {}", c))?
+                .len()
+        };
+        tokens.push(rest[..len].to_string());
+        rest = rest[len..].trim_start();
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<String>,
+    pos: usize,
+}
+
+impl Parser {
+    fn next(&mut self) -> Result<&str, String> {
+        let token = self.tokens.get(self.pos).ok_or("unexpected end")?;
+        self.pos += 1;
+        Ok(token)
+    }
+
+    fn accept(&mut self, token: &str) -> bool {
+        let found = self.tokens.get(self.pos).is_some_and(|t| t == token);
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    fn or(&mut self) -> Result<Condition, String> {
+        let mut left = self.and()?;
+        while self.accept("||") {
+            left = Condition::Or(Box::new(left), Box::new(self.and()?));
+        }
+        Ok(left)
+    }
+
+    fn and(&mut self) -> Result<Condition, String> {
+        let mut left = self.comparison()?;
+        while self.accept("&&") {
+            left = Condition::And(Box::new(left), Box::new(self.comparison()?));
+        }
+        Ok(left)
+    }
+
+    fn comparison(&mut self) -> Result<Condition, String> {
+        if self.accept("(") {
+            let condition = self.or()?;
+            if !self.accept(")") {
+                return Err("expected )".to_string());
+            }
+            return Ok(condition);
+        }
+        let left = self.operand()?;
+        let op = self.next()?;
+        let op = *COMPARISONS
+            .iter()
+            .find(|&&c| c == op)
+            .ok_or_else(|| format!("expected a comparison, found {}", op))?;
+        Ok(Condition::Compare(left, op, self.operand()?))
+    }
+
+    fn operand(&mut self) -> Result<Operand, String> {
+        let token = self.next()?;
+        if let Some(n) = register_number(token) {
+            Ok(Operand::Register(n))
+        } else if let Some(v) = asm::number(token) {
+            Ok(Operand::Value(v))
+        } else {
+            Err(format!("expected a register or number, found {}", token))
+        }
+    }
+}
+
+// Checks a tracepoint message, where {v0} or {i} stand for register values
+// and {cycle} for the instruction count
+fn check_message(message: &str) -> Result<(), String> {
+    let mut rest = message;
+    while let Some(start) = rest.find('{') {
+        let end = rest[start..].find('}').ok_or("unclosed { in message")?;
+        let name = &rest[start + 1..start + end];
+        if name != "cycle" && register_number(name).is_none() {
+            return Err(format!("unknown register {{{}}}", name));
+        }
+        rest = &rest[start + end + 1..];
+    }
+    Ok(())
+}
+
+fn format_message(message: &str, chip8: &Chip8) -> String {
+    let r = chip8.registers();
+    let mut text = String::new();
+    let mut rest = message;
+    while let Some(start) = rest.find('{') {
+        let end = start + rest[start..].find('}').unwrap_or(rest.len() - start);
+        text += &rest[..start];
+        let name = &rest[start + 1..end];
+        match register_number(name) {
+            Some(n) => text += &format!("{:x}", register(&r, n)),
+            None => text += &chip8.cycles().to_string(),
+        }
+        rest = rest.get(end + 1..).unwrap_or_default();
+    }
+    text + rest
+}
+
+// A breakpoint with an optional condition, or with a message a tracepoint
+// that prints it and goes on instead of stopping
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Breakpoint {
+    pub addr: u16,
+    pub condition: Option<Condition>,
+    // Stops from this hit on
+    pub after: u64,
+    pub hits: u64,
+    pub message: Option<String>,
+}
+
+impl Breakpoint {
+    pub fn new(addr: u16) -> Breakpoint {
+        Breakpoint {
+            addr,
+            condition: None,
+            after: 1,
+            hits: 0,
+            message: None,
+        }
+    }
+
+    // ADDR [hits N] [if CONDITION], as in the break and trace commands
+    pub fn parse(s: &str) -> Result<Breakpoint, String> {
+        let (point, condition) = match s.split_once(" if ") {
+            Some((point, condition)) => (point, Some(Condition::parse(condition)?)),
+            None => (s, None),
+        };
+        let words: Vec<&str> = point.split_whitespace().collect();
+        let addr = |s: &str| asm::number(s).filter(|&a| (a as usize) < MEMORY_SIZE);
+        let mut breakpoint = match words[..] {
+            [a] | [a, "hits", _] => {
+                Breakpoint::new(addr(a).ok_or(format!("invalid address: {}", a))?)
+            }
+            _ => return Err("expected ADDRESS [hits N] [if CONDITION]".to_string()),
+        };
+        if let [_, _, n] = words[..] {
+            breakpoint.after = n.parse().map_err(|_| format!("invalid hit count: {}", n))?;
+        }
+        breakpoint.condition = condition;
+        Ok(breakpoint)
+    }
+
+    pub fn with_message(mut self, message: &str) -> Result<Breakpoint, String> {
+        check_message(message)?;
+        self.message = Some(message.to_string());
+        Ok(self)
+    }
+}
+
+impl fmt::Display for Breakpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let kind = if self.message.is_some() {
+            "trace"
+        } else {
+            "break"
+        };
+        write!(f, "{} 0x{:03x}", kind, self.addr)?;
+        if self.after > 1 {
+            write!(f, " hits {}", self.after)?;
+        }
+        if let Some(condition) = &self.condition {
+            write!(f, " if {}", condition)?;
+        }
+        if let Some(message) = &self.message {
+            write!(f, " \"{}\"", message)?;
+        }
+        write!(f, ", hit count {}", self.hits)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+    ReadWrite,
+}
+
+// Stops after an instruction reads or writes memory in a range
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub addr: u16,
+    pub len: u16,
+    pub access: Access,
+}
+
+impl Watchpoint {
+    fn contains(&self, addr: usize) -> bool {
+        let start = self.addr as usize % MEMORY_SIZE;
+        let offset = (addr % MEMORY_SIZE + MEMORY_SIZE - start) % MEMORY_SIZE;
+        offset < self.len as usize
+    }
+}
+
+// What happened at a breakpoint
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    Stop,
+    Log(String),
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Breakpoints {
+    points: Vec<Breakpoint>,
+    watchpoints: Vec<Watchpoint>,
+}
+
+impl Breakpoints {
+    pub fn add(&mut self, breakpoint: Breakpoint) {
+        self.points.push(breakpoint);
+    }
+
+    // Deletes the breakpoints and tracepoints at an address
+    pub fn remove(&mut self, addr: u16) -> bool {
+        let len = self.points.len();
+        self.points.retain(|b| b.addr != addr);
+        self.points.len() < len
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Breakpoint> {
+        self.points.iter()
+    }
+
+    pub fn watch(&mut self, watchpoint: Watchpoint) {
+        self.watchpoints.push(watchpoint);
+    }
+
+    pub fn unwatch(&mut self, watchpoint: &Watchpoint) -> bool {
+        let found = self.watchpoints.iter().position(|w| w == watchpoint);
+        found.map(|n| self.watchpoints.remove(n)).is_some()
+    }
+
+    // Counts the hits of the breakpoints at the PC whose condition holds
+    pub fn hit(&mut self, chip8: &Chip8) -> Vec<Event> {
+        let r = chip8.registers();
+        let mut events = Vec::new();
+        for b in self.points.iter_mut().filter(|b| b.addr == r.pc) {
+            if b.condition.as_ref().is_some_and(|c| !c.holds(&r)) {
+                continue;
+            }
+            b.hits += 1;
+            if b.hits < b.after {
+                continue;
+            }
+            events.push(match &b.message {
+                Some(message) => Event::Log(format_message(message, chip8)),
+                None => Event::Stop,
+            });
+        }
+        events
+    }
+
+    // True at a breakpoint whose condition holds, not counting hits
+    pub fn matches(&self, chip8: &Chip8) -> bool {
+        let r = chip8.registers();
+        self.points.iter().any(|b| {
+            b.addr == r.pc
+                && b.message.is_none()
+                && b.condition.as_ref().is_none_or(|c| c.holds(&r))
+        })
+    }
+
+    // The watchpoint the next instruction will trigger, with the address
+    pub fn accessed(&self, chip8: &Chip8) -> Option<(Access, u16)> {
+        if self.watchpoints.is_empty() {
+            return None;
+        }
+        let r = chip8.registers();
+        let (reads, writes) = decode(chip8.opcode_at(r.pc)).memory_access();
+        let addrs = |len: u16| (0..len as usize).map(move |k| r.i as usize + k);
+        self.watchpoints.iter().find_map(|w| {
+            let found = match w.access {
+                Access::Read => addrs(reads).find(|&a| w.contains(a)),
+                Access::Write => addrs(writes).find(|&a| w.contains(a)),
+                Access::ReadWrite => addrs(reads).chain(addrs(writes)).find(|&a| w.contains(a)),
+            };
+            found.map(|a| (w.access, (a % MEMORY_SIZE) as u16))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chip8::KeyBoard;
+
+    #[test]
+    fn conditions() {
+        let c = Condition::parse("V3 == 0x10 && I > 0x300").unwrap();
+        let mut r = Registers::default();
+        r.v[3] = 0x10;
+        r.i = 0x300;
+        assert!(!c.holds(&r));
+        r.i = 0x301;
+        assert!(c.holds(&r));
+        assert_eq!("(v3 == 0x10 && i > 0x300)", c.to_string());
+
+        let c = Condition::parse("(v0 != 1 || dt <= 2) && pc >= 0x200").unwrap();
+        r.pc = 0x200;
+        assert!(c.holds(&r));
+        r.v[0] = 1;
+        r.delay_timer = 3;
+        assert!(!c.holds(&r));
+
+        for bad in [
+            "",
+            "v0",
+            "v0 = 1",
+            "vg == 1",
+            "(v0 == 1",
+            "v0 == 1 v1",
+            "v0 == 1 &",
+        ] {
+            assert!(Condition::parse(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn hits_and_messages() {
+        // V0 += 1 forever
+        let mut chip8 = Chip8::new();
+        chip8.load_rom(&[0x70, 0x01, 0x12, 0x00]).unwrap();
+        let mut breakpoints = Breakpoints::default();
+        breakpoints.add(Breakpoint::parse("0x202 hits 2 if v0 > 1").unwrap());
+        let trace = Breakpoint::parse("0x202").unwrap();
+        breakpoints.add(trace.with_message("v0={v0} at {cycle}").unwrap());
+        let late = Breakpoint::parse("0x202 hits 3").unwrap();
+        breakpoints.add(late.with_message("late v0={v0}").unwrap());
+        assert!(Breakpoint::new(0).with_message("{v0").is_err());
+        assert!(Breakpoint::new(0).with_message("{x}").is_err());
+        assert!(Breakpoint::parse("0x1000").is_err());
+        assert!(Breakpoint::parse("0x200 hits").is_err());
+
+        let kb = KeyBoard::new();
+        let mut events = Vec::new();
+        for _ in 0..5 {
+            chip8.step(&kb).unwrap();
+            events.extend(breakpoints.hit(&chip8));
+        }
+        let expected = [
+            Event::Log("v0=1 at 1".to_string()),
+            Event::Log("v0=2 at 3".to_string()),
+            Event::Stop,
+            Event::Log("v0=3 at 5".to_string()),
+            Event::Log("late v0=3".to_string()),
+        ];
+        assert_eq!(expected[..], events[..]);
+        assert!(breakpoints.matches(&chip8));
+        let listed: Vec<String> = breakpoints.iter().map(|b| b.to_string()).collect();
+        assert_eq!(
+            vec![
+                "break 0x202 hits 2 if v0 > 0x1, hit count 2",
+                "trace 0x202 \"v0={v0} at {cycle}\", hit count 3",
+                "trace 0x202 hits 3 \"late v0={v0}\", hit count 3"
+            ],
+            listed
+        );
+        assert!(breakpoints.remove(0x202));
+        assert!(breakpoints.iter().next().is_none());
+    }
+
+    #[test]
+    fn watchpoints() {
+        // I = 0xffe, draw 4 rows, store V0-V1, load V0-V3, BCD
+        let rom = [0xaf, 0xfe, 0xd0, 0x04, 0xf1, 0x55, 0xf3, 0x65, 0xf0, 0x33];
+        let mut chip8 = Chip8::new();
+        chip8.load_rom(&rom).unwrap();
+        let mut breakpoints = Breakpoints::default();
+        let write = Watchpoint {
+            addr: 0x000,
+            len: 2,
+            access: Access::Write,
+        };
+        breakpoints.watch(write);
+        breakpoints.watch(Watchpoint {
+            addr: 0x001,
+            len: 1,
+            access: Access::Read,
+        });
+
+        let kb = KeyBoard::new();
+        let mut accesses = Vec::new();
+        for _ in 0..rom.len() / 2 {
+            accesses.push(breakpoints.accessed(&chip8));
+            let _ = chip8.step(&kb);
+        }
+        let expected = [
+            None,
+            Some((Access::Read, 0x001)),
+            None,
+            Some((Access::Read, 0x001)),
+            Some((Access::Write, 0x000)),
+        ];
+        assert_eq!(expected[..], accesses[..]);
+        assert!(breakpoints.unwatch(&write));
+        assert!(!breakpoints.unwatch(&write));
+
+        // Addresses past the end wrap like the memory accesses do
+        let high = Watchpoint {
+            addr: 0x2001,
+            len: 2,
+            access: Access::Write,
+        };
+        assert!(high.contains(0x001) && high.contains(0x1002));
+        assert!(!high.contains(0x000));
+    }
+}
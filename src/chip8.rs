@@ -1,19 +1,29 @@
-use rand::Rng;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 use std::error::Error;
-use std::fs::File;
-use std::io::BufReader;
-use std::io::Read;
-use std::iter::Iterator;
+use std::fmt;
+use std::fs;
 
-const MEMORY_SIZE: usize = 4096;
+use crate::framebuffer::{DirtyRows, Framebuffer};
+use crate::instruction::{decode, Instruction};
+
+pub mod recompiler;
+
+pub const MEMORY_SIZE: usize = 4096;
 const V_SIZE: usize = 16;
 pub const GFX_SIZE_COL: usize = 64;
 pub const GFX_SIZE_ROW: usize = 32;
 pub const GFX_SIZE: usize = GFX_SIZE_COL * GFX_SIZE_ROW;
-const STACK_SIZE: usize = 16;
+pub const STACK_SIZE: usize = 16;
+pub const LOAD_ADDRESS: u16 = 0x200;
 pub const KEY_NUM: usize = 16;
 
-const CHIP8_FONTSET: [u8; 80] = [
+// Start of a save state, followed by memory, V0-VF, I, PC, SP, the load
+// address, the stack, the timers, the instruction count and the screen
+// rows, all little endian
+const STATE_MAGIC: &[u8; 4] = b"C8S1";
+
+pub(crate) const CHIP8_FONTSET: [u8; 80] = [
     0xF0, 0x90, 0x90, 0x90, 0xF0, // 0
     0x20, 0x60, 0x20, 0x20, 0x70, // 1
     0xF0, 0x10, 0xF0, 0x80, 0xF0, // 2
@@ -32,19 +42,154 @@ const CHIP8_FONTSET: [u8; 80] = [
     0xF0, 0x80, 0xF0, 0x80, 0x80, // F
 ];
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    Running,
+    // The program jumps to itself or loops without changing any state
+    Halted,
+    // The instruction at PC accessed this address outside of memory
+    // and was not executed to the end
+    Trapped(usize),
+}
+
+// What happens when an instruction accesses memory beyond 0xFFF
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MemoryPolicy {
+    // Mask the address to 12 bits as the real hardware does
+    #[default]
+    Wrap,
+    // Fail the instruction with an error
+    Error,
+    // Stop at the instruction and report `State::Trapped`
+    Trap,
+}
+
+// Behaviours that differ between CHIP-8 interpreters
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Quirks {
+    // 0x8XY1, 0x8XY2, 0x8XY3 reset VF to 0
+    pub vf_reset: bool,
+    // 0xFX55, 0xFX65 increment I by X + 1
+    pub memory: bool,
+    // 0x8XY6, 0x8XYE shift VX in place instead of VY
+    pub shifting: bool,
+    // 0xBNNN jumps to XNN plus VX instead of NNN plus V0
+    pub jumping: bool,
+    // 0xDXYN clips sprites at the screen edge instead of wrapping them
+    pub clipping: bool,
+}
+
+pub const QUIRK_PROFILES: [&str; 3] = ["default", "chip8", "schip"];
+
+impl Quirks {
+    pub fn from_profile(name: &str) -> Option<Quirks> {
+        match name {
+            "default" => Some(Quirks::default()),
+            // COSMAC VIP
+            "chip8" => Some(Quirks {
+                vf_reset: true,
+                memory: true,
+                shifting: false,
+                jumping: false,
+                clipping: true,
+            }),
+            // SUPER-CHIP 1.1
+            "schip" => Some(Quirks {
+                vf_reset: false,
+                memory: false,
+                shifting: true,
+                jumping: true,
+                clipping: true,
+            }),
+            _ => None,
+        }
+    }
+
+    // The profile with these quirks, if any
+    pub fn profile(&self) -> Option<&'static str> {
+        QUIRK_PROFILES
+            .into_iter()
+            .find(|name| Quirks::from_profile(name) == Some(*self))
+    }
+}
+
+impl Default for Quirks {
+    fn default() -> Quirks {
+        Quirks {
+            vf_reset: false,
+            memory: false,
+            shifting: true,
+            jumping: false,
+            clipping: false,
+        }
+    }
+}
+
+// Machine state seen at the last backward jump, used for idle loop detection
+#[derive(Clone, PartialEq)]
+struct LoopCheck {
+    pc: u16,
+    v: [u8; V_SIZE],
+    i: u16,
+    sp: u16,
+}
+
+// The registers, for debuggers
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Registers {
+    pub v: [u8; V_SIZE],
+    pub i: u16,
+    pub pc: u16,
+    pub sp: u16,
+    pub delay_timer: u8,
+    pub sound_timer: u8,
+}
+
+#[derive(Clone)]
 pub struct Chip8 {
     memory: [u8; MEMORY_SIZE],
     v: [u8; V_SIZE],
     i: u16,
     pc: u16,
-    pub gfx: [u8; GFX_SIZE],
+    pub gfx: Framebuffer,
     delay_timer: u8,
     sound_timer: u8,
     stack: [u16; STACK_SIZE],
     sp: u16,
+    load_address: u16,
     draw_flag: bool,
+    quirks: Quirks,
+    rng: StdRng,
+    memory_policy: MemoryPolicy,
+    trap: Option<usize>,
+    loop_check: Option<LoopCheck>,
+    side_effect: bool,
+    // Decoded instruction at each address, with its opcode
+    cache: Vec<Option<(u16, Instruction)>>,
+    // Lowest and highest address of the code written since the recompiler last looked
+    code_writes: Option<(usize, usize)>,
+    // Instructions executed
+    cycles: u64,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RomTooLarge {
+    pub size: usize,
+    pub max: usize,
+}
+
+impl fmt::Display for RomTooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "ROM too large: {} bytes, at most {} bytes fit",
+            self.size, self.max
+        )
+    }
+}
+
+impl Error for RomTooLarge {}
+
 pub struct KeyBoard {
     pub fin_flag: bool,
     pub key: [u8; KEY_NUM],
@@ -53,226 +198,535 @@ pub struct KeyBoard {
 impl Chip8 {
     pub fn new() -> Chip8 {
         let mut initial_memory = [0; MEMORY_SIZE];
-        for i in 0..80 {
-            initial_memory[i] = CHIP8_FONTSET[i];
-        }
+        initial_memory[..80].copy_from_slice(&CHIP8_FONTSET);
         Chip8 {
             memory: initial_memory,
             v: [0; V_SIZE],
-            i: 0,      // Reset inex reister
-            pc: 0x200, // Program cunter starts at 0x200
-            gfx: [0; GFX_SIZE],
+            i: 0,             // Reset inex reister
+            pc: LOAD_ADDRESS, // Program cunter starts at 0x200
+            gfx: Framebuffer::new(),
             delay_timer: 0,
             sound_timer: 0,
             stack: [0; STACK_SIZE],
             sp: 0, // Rese stack posinter
+            load_address: LOAD_ADDRESS,
             draw_flag: false,
+            quirks: Quirks::default(),
+            rng: StdRng::from_entropy(),
+            memory_policy: MemoryPolicy::default(),
+            trap: None,
+            loop_check: None,
+            side_effect: false,
+            cache: vec![None; MEMORY_SIZE],
+            code_writes: None,
+            cycles: 0,
         }
     }
 
+    // Sets where ROMs are loaded and where the program starts,
+    // e.g. 0x600 for ETI-660 programs
+    pub fn set_load_address(&mut self, addr: u16) -> Result<(), String> {
+        if addr as usize >= MEMORY_SIZE {
+            return Err(format!("load address out of memory: 0x{:x}", addr));
+        }
+        self.load_address = addr;
+        self.pc = addr;
+        Ok(())
+    }
+
     pub fn load_game(&mut self, filename: &str) -> Result<(), Box<dyn Error>> {
-        let buf = BufReader::new(File::open(filename)?);
-        for (i, byte_or_error) in buf.bytes().enumerate() {
-            let byte = byte_or_error.unwrap();
-            self.memory[0x200 + i] = byte;
+        let rom = fs::read(filename)?;
+        self.load_rom(&rom)?;
+        Ok(())
+    }
+
+    pub fn load_rom(&mut self, rom: &[u8]) -> Result<(), RomTooLarge> {
+        let begin = self.load_address as usize;
+        let max = MEMORY_SIZE.saturating_sub(begin);
+        if rom.len() > max {
+            return Err(RomTooLarge {
+                size: rom.len(),
+                max,
+            });
         }
+        self.memory[begin..begin + rom.len()].copy_from_slice(rom);
+        self.cache.fill(None);
+        self.code_written(0, MEMORY_SIZE - 1);
         Ok(())
     }
 
-    pub fn emulate_cycle(&mut self, kb: &KeyBoard) {
-        // Fetch Opcode
-        let opcode: u16 = {
-            let m0 = self.memory[self.pc as usize] as u16;
-            let m1 = self.memory[self.pc as usize + 1] as u16;
-            m0 << 8 | m1
-        };
+    // Restarts the program: registers, stack, timers and screen go back to
+    // their initial state, memory and settings are kept
+    pub fn reset(&mut self) {
+        self.v = [0; V_SIZE];
+        self.i = 0;
+        self.pc = self.load_address;
+        self.gfx.clear();
+        self.delay_timer = 0;
+        self.sound_timer = 0;
+        self.stack = [0; STACK_SIZE];
+        self.sp = 0;
+        self.draw_flag = true;
+        self.trap = None;
+        self.loop_check = None;
+        self.side_effect = false;
+        self.cycles = 0;
+    }
 
-        // Decode Opcode
+    // Clears memory back to just the font, for loading another ROM
+    pub fn clear_memory(&mut self) {
+        self.memory = [0; MEMORY_SIZE];
+        self.memory[..80].copy_from_slice(&CHIP8_FONTSET);
+        self.cache.fill(None);
+        self.code_written(0, MEMORY_SIZE - 1);
+    }
+
+    pub fn set_quirks(&mut self, quirks: Quirks) {
+        self.quirks = quirks;
+    }
+
+    pub fn quirks(&self) -> Quirks {
+        self.quirks
+    }
+
+    // Makes 0xCXNN deterministic
+    pub fn set_seed(&mut self, seed: u64) {
+        self.rng = StdRng::seed_from_u64(seed);
+    }
+
+    pub fn set_memory_policy(&mut self, policy: MemoryPolicy) {
+        self.memory_policy = policy;
+    }
+
+    pub fn emulate_cycle(&mut self, kb: &KeyBoard) -> Result<State, String> {
+        let state = self.step(kb)?;
+        self.update_timers();
+        Ok(state)
+    }
+
+    // Executes up to `instructions` instructions, stopping early when the
+    // program stops, and then updates the timers once
+    pub fn run_frame(&mut self, kb: &KeyBoard, instructions: usize) -> Result<State, String> {
+        let mut state = State::Running;
+        for _ in 0..instructions {
+            state = self.step(kb)?;
+            if state != State::Running {
+                break;
+            }
+        }
+        self.update_timers();
+        Ok(state)
+    }
+
+    // Executes one instruction without touching the timers
+    pub fn step(&mut self, kb: &KeyBoard) -> Result<State, String> {
+        let (opcode, instruction) = match self.fetch_decoded() {
+            Ok(decoded) => decoded,
+            Err(e) => return self.fault(e),
+        };
         // Execute Opcode
-        if let Err(e) = self.decode_execute(opcode, kb) {
-            self.dump();
-            panic!("decode_execute: {}", e);
+        let pc = self.pc;
+        let result = self.execute(instruction, kb);
+        self.complete(pc, opcode, result)
+    }
+
+    // Turns the result of the instruction at `pc` into the state of the machine
+    #[inline]
+    fn complete(
+        &mut self,
+        pc: u16,
+        opcode: u16,
+        result: Result<(), String>,
+    ) -> Result<State, String> {
+        self.cycles += 1;
+        if let Err(e) = result {
+            return self.fault(e);
+        }
+
+        // Detect halt
+        if opcode & 0xF000 == 0x1000 && self.pc <= pc {
+            Ok(self.check_idle_loop(pc))
+        } else {
+            Ok(State::Running)
+        }
+    }
+
+    // A memory trap stops the machine, any other error is returned
+    fn fault(&mut self, e: String) -> Result<State, String> {
+        match self.trap.take() {
+            Some(addr) => Ok(State::Trapped(addr)),
+            None => Err(e),
         }
+    }
 
-        // Update timers
+    pub(crate) fn fetch(&mut self) -> Result<u16, String> {
+        let pc = self.resolve(self.pc as usize)?;
+        self.pc = pc as u16;
+        let m0 = self.load(pc)? as u16;
+        let m1 = self.load(pc + 1)? as u16;
+        Ok(m0 << 8 | m1)
+    }
+
+    // Decodes the opcode at pc the first time it runs and reuses that afterwards
+    fn fetch_decoded(&mut self) -> Result<(u16, Instruction), String> {
+        let pc = self.resolve(self.pc as usize)?;
+        self.pc = pc as u16;
+        self.decode_at(pc)
+    }
+
+    #[inline]
+    fn decode_at(&mut self, addr: usize) -> Result<(u16, Instruction), String> {
+        if let Some(cached) = self.cache[addr] {
+            return Ok(cached);
+        }
+        let opcode = (self.load(addr)? as u16) << 8 | self.load(addr + 1)? as u16;
+        let decoded = (opcode, decode(opcode));
+        self.cache[addr] = Some(decoded);
+        Ok(decoded)
+    }
+
+    // Decrements the timers, called at 60 Hz
+    pub fn update_timers(&mut self) {
         if self.delay_timer > 0 {
             self.delay_timer -= 1;
         }
         if self.sound_timer > 0 {
-            if self.sound_timer == 1 {
-                println!("BEEP!");
-            }
             self.sound_timer -= 1;
         }
     }
-    fn decode_execute(&mut self, opcode: u16, kb: &KeyBoard) -> Result<(), String> {
-        match opcode & 0xF000 {
-            0x0000 => match opcode {
-                0x00E0 => {
-                    // 0x00E0: Clears the screen
-                    self.gfx = [0; GFX_SIZE];
-                    self.draw_flag = true;
-                    self.pc += 2;
+
+    // Number of instructions executed so far
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    pub fn registers(&self) -> Registers {
+        Registers {
+            v: self.v,
+            i: self.i,
+            pc: self.pc,
+            sp: self.sp,
+            delay_timer: self.delay_timer,
+            sound_timer: self.sound_timer,
+        }
+    }
+
+    pub fn set_registers(&mut self, r: Registers) {
+        self.v = r.v;
+        self.i = r.i;
+        self.pc = r.pc;
+        self.sp = r.sp;
+        self.delay_timer = r.delay_timer;
+        self.sound_timer = r.sound_timer;
+    }
+
+    pub fn memory(&self) -> &[u8] {
+        &self.memory
+    }
+
+    // Addresses of the calls that have not returned yet, the first call first
+    pub fn stack(&self) -> &[u16] {
+        &self.stack[..(self.sp as usize).min(STACK_SIZE)]
+    }
+
+    // The opcode at an address, wrapping at the end of memory
+    pub fn opcode_at(&self, addr: u16) -> u16 {
+        let addr = addr as usize % MEMORY_SIZE;
+        (self.memory[addr] as u16) << 8 | self.memory[(addr + 1) % MEMORY_SIZE] as u16
+    }
+
+    // Writes memory from outside the program, e.g. from a debugger
+    pub fn write_memory(&mut self, addr: usize, bytes: &[u8]) -> Result<(), String> {
+        let end = addr
+            .checked_add(bytes.len())
+            .filter(|&end| end <= MEMORY_SIZE)
+            .ok_or_else(|| format!("write outside of memory: 0x{:x}", addr))?;
+        if bytes.is_empty() {
+            return Ok(());
+        }
+        self.memory[addr..end].copy_from_slice(bytes);
+        let lo = addr.saturating_sub(1);
+        self.cache[lo..end].fill(None);
+        self.code_written(lo, end - 1);
+        Ok(())
+    }
+
+    // The machine as a save state, without the quirks and memory policy
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut state = STATE_MAGIC.to_vec();
+        state.extend_from_slice(&self.memory);
+        state.extend_from_slice(&self.v);
+        for word in [self.i, self.pc, self.sp, self.load_address] {
+            state.extend_from_slice(&word.to_le_bytes());
+        }
+        for addr in self.stack {
+            state.extend_from_slice(&addr.to_le_bytes());
+        }
+        state.extend_from_slice(&[self.delay_timer, self.sound_timer]);
+        state.extend_from_slice(&self.cycles.to_le_bytes());
+        for row in self.gfx.rows() {
+            state.extend_from_slice(&row.to_le_bytes());
+        }
+        state
+    }
+
+    // Restores a save state, leaving the machine as it was if it is invalid
+    pub fn load_state(&mut self, mut state: &[u8]) -> Result<(), String> {
+        if take(&mut state, STATE_MAGIC.len())? != STATE_MAGIC {
+            return Err("not a save state".to_string());
+        }
+        let memory = take(&mut state, MEMORY_SIZE)?;
+        let v = take(&mut state, V_SIZE)?;
+        let mut words = [0; 4 + STACK_SIZE];
+        for word in words.iter_mut() {
+            *word = u16::from_le_bytes(take(&mut state, 2)?.try_into().unwrap());
+        }
+        let timers = take(&mut state, 2)?;
+        let cycles = u64::from_le_bytes(take(&mut state, 8)?.try_into().unwrap());
+        let mut rows = [0; GFX_SIZE_ROW];
+        for row in rows.iter_mut() {
+            *row = u64::from_le_bytes(take(&mut state, 8)?.try_into().unwrap());
+        }
+        if !state.is_empty() {
+            return Err("save state is too long".to_string());
+        }
+        let [i, pc, sp, load_address] = [words[0], words[1], words[2], words[3]];
+        if sp as usize > STACK_SIZE || load_address as usize >= MEMORY_SIZE {
+            return Err("invalid save state".to_string());
+        }
+
+        self.memory.copy_from_slice(memory);
+        self.v.copy_from_slice(v);
+        (self.i, self.pc, self.sp, self.load_address) = (i, pc, sp, load_address);
+        self.stack.copy_from_slice(&words[4..]);
+        (self.delay_timer, self.sound_timer) = (timers[0], timers[1]);
+        self.cycles = cycles;
+        self.gfx.clear();
+        for (y, &row) in rows.iter().enumerate() {
+            self.gfx.xor_row(y, row);
+        }
+        self.draw_flag = true;
+        self.trap = None;
+        self.loop_check = None;
+        self.side_effect = false;
+        self.cache.fill(None);
+        self.code_written(0, MEMORY_SIZE - 1);
+        Ok(())
+    }
+
+    // The buzzer sounds while the sound timer is non-zero
+    pub fn sound(&self) -> bool {
+        self.sound_timer > 0
+    }
+
+    // Called on every backward jump. The loop is idle if nothing but the
+    // registers changed since the last jump and the registers are the same.
+    fn check_idle_loop(&mut self, pc: u16) -> State {
+        if self.pc == pc {
+            // 0x1NNN jumps to itself
+            return State::Halted;
+        }
+        let check = LoopCheck {
+            pc: self.pc,
+            v: self.v,
+            i: self.i,
+            sp: self.sp,
+        };
+        let idle = !self.side_effect && self.loop_check.as_ref() == Some(&check);
+        self.loop_check = Some(check);
+        self.side_effect = false;
+        if idle {
+            State::Halted
+        } else {
+            State::Running
+        }
+    }
+    pub(crate) fn decode_execute(&mut self, opcode: u16, kb: &KeyBoard) -> Result<(), String> {
+        self.execute(decode(opcode), kb)
+    }
+
+    pub(crate) fn execute(
+        &mut self,
+        instruction: Instruction,
+        kb: &KeyBoard,
+    ) -> Result<(), String> {
+        use Instruction::*;
+        match instruction {
+            Cls => {
+                // 0x00E0: Clears the screen
+                self.gfx.clear();
+                self.draw_flag = true;
+                self.side_effect = true;
+                self.pc += 2;
+            }
+            Ret => {
+                // 0x00EE: Returns from a subroutine
+                // pop
+                if self.sp == 0 {
+                    return Err("stack underflow".to_string());
                 }
-                0x00EE => {
-                    // 0x00EE: Returns from a subroutine
-                    // pop
-                    self.sp -= 1;
-                    let pc = self.stack[self.sp as usize];
-                    // update
-                    self.pc = pc + 2;
+                if self.sp as usize > STACK_SIZE {
+                    return Err(format!("stack pointer out of range: {}", self.sp));
                 }
-                _ => return Err(format!("unknown opcode(0x0000): 0x{:x}", opcode)),
-            },
-            0x1000 => {
+                self.sp -= 1;
+                let pc = self.stack[self.sp as usize];
+                // update
+                self.pc = pc + 2;
+            }
+            Jp(nnn) => {
                 // 0x1NNN: Jumps to address NNN
-                let nnn = opcode & 0x0FFF;
                 self.pc = nnn;
             }
-            0x2000 => {
+            Call(nnn) => {
                 // 0x2NNN: Calls  subroutine at NNN
                 // push
+                if self.sp as usize >= STACK_SIZE {
+                    return Err("stack overflow".to_string());
+                }
                 self.stack[self.sp as usize] = self.pc;
                 self.sp += 1;
                 // update
-                let nnn = opcode & 0x0FFF;
                 self.pc = nnn;
             }
-            0x3000 => {
+            SeImm(x, nn) => {
                 // 0x3XNN: Skips the next instrunction if VX == NN
-                let x = ((opcode & 0x0F00) >> 8) as usize;
-                let nn = (opcode & 0x00FF) as u8;
-                if self.v[x] == nn {
+                if self.v[x as usize] == nn {
                     self.pc += 4; // skip
                 } else {
                     self.pc += 2;
                 }
             }
-            0x4000 => {
+            SneImm(x, nn) => {
                 // 0x4XNN: Skips the next instrunction if VX != NN
-                let x = ((opcode & 0x0F00) >> 8) as usize;
-                let nn = (opcode & 0x00FF) as u8;
-                if self.v[x] != nn {
+                if self.v[x as usize] != nn {
                     self.pc += 4; // skip
                 } else {
                     self.pc += 2;
                 }
             }
-            0x5000 => {
+            SeReg(x, y) => {
                 // 0x5XY0: Skips the next instrunction if VX == VY
-                if opcode & 0x000F != 0x0000 {
-                    return Err(format!("unknown opcode(0x5000): 0x{:x}", opcode));
-                }
-                let x = ((opcode & 0x0F00) >> 8) as usize;
-                let y = ((opcode & 0x00F0) >> 4) as usize;
-                if self.v[x] == self.v[y] {
+                if self.v[x as usize] == self.v[y as usize] {
                     self.pc += 4; // skip
                 } else {
                     self.pc += 2;
                 }
             }
-            0x6000 => {
+            LdImm(x, nn) => {
                 // 0x6XNN: Sets VX to NN
-                let x = ((opcode & 0x0F00) >> 8) as usize;
-                let nn = (opcode & 0x00FF) as u8;
-                self.v[x] = nn;
+                self.v[x as usize] = nn;
                 self.pc += 2;
             }
-            0x7000 => {
+            AddImm(x, nn) => {
                 // 0x7XNN: Adds NN to VX
-                let x = ((opcode & 0x0F00) >> 8) as usize;
-                let nn = (opcode & 0x00FF) as u8;
+                let x = x as usize;
                 let (ans, _) = self.v[x].overflowing_add(nn);
 
                 self.v[x] = ans;
                 self.pc += 2;
             }
-            0x8000 => {
-                let x = ((opcode & 0x0F00) >> 8) as usize;
-                let y = ((opcode & 0x00F0) >> 4) as usize;
-                match opcode & 0x000F {
-                    0x0000 => {
-                        // 0x8XY0: Sets VX to the value of VY
-                        self.v[x] = self.v[y];
-                    }
-                    0x0001 => {
-                        // 0x8XY1: Sets VX to VX or VY
-                        self.v[x] |= self.v[y];
-                    }
-                    0x0002 => {
-                        // 0x8XY2: Sets VX to VX and VY
-                        self.v[x] &= self.v[y];
-                    }
-                    0x0003 => {
-                        // 0x8XY3: Sets VX to VX xor VY
-                        self.v[x] ^= self.v[y];
-                    }
-                    0x0004 => {
-                        // 0x8XY4: Add VY to VX with carry
-                        let (ans, ovfl) = self.v[x].overflowing_add(self.v[y]);
-                        self.v[0xf] = if ovfl { 1 } else { 0 };
-                        self.v[x] = ans;
-                    }
-                    0x0005 => {
-                        // 0x8XY5: VY is subtracted from VX with carry
-                        let (ans, ovfl) = self.v[x].overflowing_sub(self.v[y]);
-                        self.v[0xf] = if ovfl { 1 } else { 0 };
-                        self.v[x] = ans;
-                    }
-                    0x0006 => {
-                        // 0x8XY6: Stores the least significant bit of VX in VF and VX >>= 1
-                        self.v[0xf] = self.v[x] & 0x01;
-                        self.v[x] >>= 1;
-                    }
-                    0x0007 => {
-                        // 0x8XY7: Sets VX to VY minus VX with carry
-                        let (ans, ovfl) = self.v[y].overflowing_sub(self.v[x]);
-                        self.v[0xf] = if ovfl { 1 } else { 0 };
-                        self.v[x] = ans;
-                    }
-                    0x000E => {
-                        // 0x8XYE: Stores the most significant bit of VX in VF and VX <<= 1
-                        self.v[0xf] = (self.v[x] & 0x80) >> 7;
-                        self.v[x] <<= 1;
-                    }
-                    _ => return Err(format!("unknown opcode(0x8000): 0x{:x}", opcode)),
+            LdReg(x, y) => {
+                // 0x8XY0: Sets VX to the value of VY
+                self.v[x as usize] = self.v[y as usize];
+                self.pc += 2;
+            }
+            Or(x, y) => {
+                // 0x8XY1: Sets VX to VX or VY
+                self.v[x as usize] |= self.v[y as usize];
+                if self.quirks.vf_reset {
+                    self.v[0xf] = 0;
                 }
                 self.pc += 2;
             }
-            0x9000 => {
-                // 0x9XY0: Skips the next instrunction if VX != VY
-                if opcode & 0x000F != 0x0000 {
-                    return Err(format!("unknown opcode(0x9000): 0x{:x}", opcode));
+            And(x, y) => {
+                // 0x8XY2: Sets VX to VX and VY
+                self.v[x as usize] &= self.v[y as usize];
+                if self.quirks.vf_reset {
+                    self.v[0xf] = 0;
+                }
+                self.pc += 2;
+            }
+            Xor(x, y) => {
+                // 0x8XY3: Sets VX to VX xor VY
+                self.v[x as usize] ^= self.v[y as usize];
+                if self.quirks.vf_reset {
+                    self.v[0xf] = 0;
+                }
+                self.pc += 2;
+            }
+            AddReg(x, y) => {
+                // 0x8XY4: Add VY to VX with carry
+                let (x, y) = (x as usize, y as usize);
+                let (ans, ovfl) = self.v[x].overflowing_add(self.v[y]);
+                self.v[x] = ans;
+                self.v[0xf] = if ovfl { 1 } else { 0 };
+                self.pc += 2;
+            }
+            Sub(x, y) => {
+                // 0x8XY5: VY is subtracted from VX, VF is 0 on a borrow and 1 otherwise
+                let (x, y) = (x as usize, y as usize);
+                let (ans, borrow) = self.v[x].overflowing_sub(self.v[y]);
+                self.v[x] = ans;
+                self.v[0xf] = if borrow { 0 } else { 1 };
+                self.pc += 2;
+            }
+            Shr(x, y) => {
+                // 0x8XY6: Stores the least significant bit of VX in VF and VX >>= 1
+                let (x, y) = (x as usize, y as usize);
+                if !self.quirks.shifting {
+                    self.v[x] = self.v[y];
                 }
-                let x = ((opcode & 0x0F00) >> 8) as usize;
-                let y = ((opcode & 0x00F0) >> 4) as usize;
-                if self.v[x] != self.v[y] {
+                let vf = self.v[x] & 0x01;
+                self.v[x] >>= 1;
+                self.v[0xf] = vf;
+                self.pc += 2;
+            }
+            Subn(x, y) => {
+                // 0x8XY7: Sets VX to VY minus VX, VF is 0 on a borrow and 1 otherwise
+                let (x, y) = (x as usize, y as usize);
+                let (ans, borrow) = self.v[y].overflowing_sub(self.v[x]);
+                self.v[x] = ans;
+                self.v[0xf] = if borrow { 0 } else { 1 };
+                self.pc += 2;
+            }
+            Shl(x, y) => {
+                // 0x8XYE: Stores the most significant bit of VX in VF and VX <<= 1
+                let (x, y) = (x as usize, y as usize);
+                if !self.quirks.shifting {
+                    self.v[x] = self.v[y];
+                }
+                let vf = (self.v[x] & 0x80) >> 7;
+                self.v[x] <<= 1;
+                self.v[0xf] = vf;
+                self.pc += 2;
+            }
+            SneReg(x, y) => {
+                // 0x9XY0: Skips the next instrunction if VX != VY
+                if self.v[x as usize] != self.v[y as usize] {
                     self.pc += 4; // skip
                 } else {
                     self.pc += 2;
                 }
             }
-            0xA000 => {
+            LdI(nnn) => {
                 // 0xANNN: Set I to the address NNN
-                let nnn = opcode & 0x0FFF;
                 self.i = nnn;
                 self.pc += 2;
             }
-            0xB000 => {
+            JpV0(nnn, x) => {
                 // 0xBNNN: Jumps to address NNN plus V0
-                let nnn = opcode & 0x0FFF;
-                let v0 = self.v[0] as u16;
+                let x = if self.quirks.jumping { x as usize } else { 0 };
+                let v0 = self.v[x] as u16;
                 self.pc = v0 + nnn;
             }
-            0xC000 => {
+            Rnd(x, nn) => {
                 // 0xCXNN: Sets VX to the bitwise and operation on an random number and NN
-                let x = ((opcode & 0x0F00) >> 8) as usize;
-                let nn = (opcode & 0x00FF) as u8;
-                let r = rand::thread_rng().gen_range(1..=255);
-                self.v[x] = r & nn;
+                let r = self.rng.gen_range(1..=255);
+                self.v[x as usize] = r & nn;
+                self.side_effect = true;
                 self.pc += 2;
             }
-            0xD000 => {
+            Drw(x, y, n) => {
                 // 0xDXYN:
                 // Draws a sprite at coordinate (VX, VY)
                 // that has a width of 8 pixels and a height of N pixels.
@@ -283,144 +737,212 @@ impl Chip8 {
                 // VF is set to 1 if any screen pixels are flipped
                 // from set to unset when the sprite is drawn,
                 // and to 0 if that does not happen.
-                let x = ((opcode & 0x0F00) >> 8) as usize;
-                let y = ((opcode & 0x00F0) >> 4) as usize;
-                let n = (opcode & 0x000F) as usize;
-                let vx = self.v[x] as usize;
-                let vy = self.v[y] as usize;
+                let vx = self.v[x as usize] as usize % GFX_SIZE_COL;
+                let vy = self.v[y as usize] as usize % GFX_SIZE_ROW;
+                let rows = if self.quirks.clipping {
+                    (n as usize).min(GFX_SIZE_ROW - vy)
+                } else {
+                    n as usize
+                };
+                // Every row is read before drawing, so a sprite that can't be
+                // read leaves the screen and VF as they were
+                let mut sprite = [0; 15];
+                for (yline, row) in sprite.iter_mut().enumerate().take(rows) {
+                    *row = self.load(self.i as usize + yline)?;
+                }
                 let mut vf = 0;
-                for yline in 0..n {
-                    let pixel = self.memory[self.i as usize + yline];
-                    for xline in 0..8 {
-                        if pixel & (0x80 >> xline) != 0 {
-                            let index_x = (vx + xline) % GFX_SIZE_COL;
-                            let index_y = (vy + yline) % GFX_SIZE_ROW;
-                            let index = index_y * GFX_SIZE_COL + index_x;
-                            if self.gfx[index] == 1 {
-                                vf = 1;
-                            }
-                            self.gfx[index] ^= 1;
-                        }
+                for (yline, &row) in sprite.iter().enumerate().take(rows) {
+                    // The sprite row at the left edge, then moved to column VX
+                    let sprite = (row as u64) << (GFX_SIZE_COL - 8);
+                    let bits = if self.quirks.clipping {
+                        sprite >> vx
+                    } else {
+                        sprite.rotate_right(vx as u32)
+                    };
+                    if self.gfx.xor_row((vy + yline) % GFX_SIZE_ROW, bits) {
+                        vf = 1;
                     }
                 }
                 self.v[0xf] = vf;
                 self.draw_flag = true;
+                self.side_effect = true;
                 self.pc += 2;
             }
-            0xE000 => {
-                let x = ((opcode & 0x0F00) >> 8) as usize;
-                match opcode & 0x00FF {
-                    0x009E => {
-                        // 0xEX9E: Skips the next instruction
-                        // if the key stored in VX is pressed
-                        if kb.key[self.v[x] as usize] != 0 {
-                            self.pc += 4; // skip
-                        } else {
-                            self.pc += 2;
-                        }
-                    }
-                    0x00A1 => {
-                        // 0xEXA1: Skips the next instruction
-                        // if the key stored in VX is not pressed
-                        if kb.key[self.v[x] as usize] == 0 {
-                            self.pc += 4; // skip
-                        } else {
-                            self.pc += 2;
-                        }
-                    }
-                    _ => return Err(format!("unknown opcode(0xE000): 0x{:x}", opcode)),
+            Skp(x) => {
+                // 0xEX9E: Skips the next instruction
+                // if the key stored in VX is pressed
+                let vx = self.v[x as usize];
+                self.side_effect = true;
+                check_key(vx)?;
+                if kb.key[vx as usize] != 0 {
+                    self.pc += 4; // skip
+                } else {
+                    self.pc += 2;
                 }
             }
-            0xF000 => {
-                let x = ((opcode & 0x0F00) >> 8) as usize;
-                match opcode & 0x00FF {
-                    0x0007 => {
-                        // 0xFX07: Sets VX to the value of the delay timer
-                        self.v[x] = self.delay_timer;
-                        self.pc += 2;
-                    }
-                    0x000A => {
-                        // 0xFX0A: A key press is awaited, and then stored in VX
-                        for k in 0..KEY_NUM {
-                            if kb.key[k] != 0 {
-                                self.v[x] = k as u8;
-                                self.pc += 2;
-                                break;
-                            }
-                        }
-                    }
-                    0x0015 => {
-                        // 0xFX15: Set delay timer to VX
-                        self.delay_timer = self.v[x];
-                        self.pc += 2;
-                    }
-                    0x0018 => {
-                        // 0xFX18: Set sound timer to VX
-                        self.sound_timer = self.v[x];
-                        self.pc += 2;
-                    }
-                    0x001E => {
-                        // 0xFX1E: Adds VX to I
-                        self.i += self.v[x] as u16;
-                        self.pc += 2;
-                    }
-                    0x0029 => {
-                        // 0xFX29: Sets I to the location of the sprite for the character in VX
-                        let c = self.v[x];
-                        if c > 0xf {
-                            return Err(format!("0xFX29 invalid character: {:x}", c));
-                        }
-                        self.i = (c as u16) * 5;
-                        self.pc += 2;
-                    }
-                    0x0033 => {
-                        // 0xFX33:
-                        // Stores the binary-coded decimal representation of VX,
-                        // with the hundreds digit in memory at location in I,
-                        // the tens digit at location I+1, and the ones digit at location I+2.
-                        let vx = self.v[x];
-                        let i = self.i as usize;
-                        self.memory[i] = vx / 100;
-                        self.memory[i + 1] = (vx / 10) % 10;
-                        self.memory[i + 2] = vx % 10;
-                        self.pc += 2;
-                    }
-                    0x0055 => {
-                        // 0xFX55:
-                        // Stores from V0 to VX (including VX) in memory, starting at address I.
-                        // The offset from I is increased by 1 for each value written, but I itself is left unmodified.
-                        for j in 0..=x {
-                            self.memory[self.i as usize + j] = self.v[j];
-                        }
-                        self.pc += 2;
-                    }
-                    0x0065 => {
-                        // 0xFX65:
-                        // Fills from V0 to VX (including VX) with values from memory, starting at address I.
-                        // The offset from I is increased by 1 for each value read, but I itself is left unmodified.
-                        for j in 0..=x {
-                            self.v[j] = self.memory[self.i as usize + j];
-                        }
+            Sknp(x) => {
+                // 0xEXA1: Skips the next instruction
+                // if the key stored in VX is not pressed
+                let vx = self.v[x as usize];
+                self.side_effect = true;
+                check_key(vx)?;
+                if kb.key[vx as usize] == 0 {
+                    self.pc += 4; // skip
+                } else {
+                    self.pc += 2;
+                }
+            }
+            LdVxDt(x) => {
+                // 0xFX07: Sets VX to the value of the delay timer
+                self.v[x as usize] = self.delay_timer;
+                // The timer counts down between frames, so a loop reading it is not idle
+                self.side_effect = true;
+                self.pc += 2;
+            }
+            LdVxK(x) => {
+                // 0xFX0A: A key press is awaited, and then stored in VX
+                self.side_effect = true;
+                for k in 0..KEY_NUM {
+                    if kb.key[k] != 0 {
+                        self.v[x as usize] = k as u8;
                         self.pc += 2;
+                        break;
                     }
-                    _ => return Err(format!("unknown opcode(0xF000): 0x{:x}", opcode)),
                 }
             }
-            _ => return Err(format!("unknown opcode: 0x{:x}", opcode)),
+            LdDtVx(x) => {
+                // 0xFX15: Set delay timer to VX
+                self.delay_timer = self.v[x as usize];
+                self.side_effect = true;
+                self.pc += 2;
+            }
+            LdStVx(x) => {
+                // 0xFX18: Set sound timer to VX
+                self.sound_timer = self.v[x as usize];
+                self.side_effect = true;
+                self.pc += 2;
+            }
+            AddI(x) => {
+                // 0xFX1E: Adds VX to I
+                self.i = self.i.wrapping_add(self.v[x as usize] as u16);
+                self.pc += 2;
+            }
+            LdF(x) => {
+                // 0xFX29: Sets I to the location of the sprite for the character in VX
+                let c = self.v[x as usize];
+                if c > 0xf {
+                    return Err(format!("0xFX29 invalid character: {:x}", c));
+                }
+                self.i = (c as u16) * 5;
+                self.pc += 2;
+            }
+            LdB(x) => {
+                // 0xFX33:
+                // Stores the binary-coded decimal representation of VX,
+                // with the hundreds digit in memory at location in I,
+                // the tens digit at location I+1, and the ones digit at location I+2.
+                let vx = self.v[x as usize];
+                let i = self.i as usize;
+                self.store(i, vx / 100)?;
+                self.store(i + 1, (vx / 10) % 10)?;
+                self.store(i + 2, vx % 10)?;
+                self.side_effect = true;
+                self.pc += 2;
+            }
+            Store(x) => {
+                // 0xFX55:
+                // Stores from V0 to VX (including VX) in memory, starting at address I.
+                // The offset from I is increased by 1 for each value written, but I itself is left unmodified.
+                let x = x as usize;
+                for j in 0..=x {
+                    self.store(self.i as usize + j, self.v[j])?;
+                }
+                if self.quirks.memory {
+                    self.i = self.i.wrapping_add(x as u16 + 1);
+                }
+                self.side_effect = true;
+                self.pc += 2;
+            }
+            Load(x) => {
+                // 0xFX65:
+                // Fills from V0 to VX (including VX) with values from memory, starting at address I.
+                // The offset from I is increased by 1 for each value read, but I itself is left unmodified.
+                let x = x as usize;
+                for j in 0..=x {
+                    self.v[j] = self.load(self.i as usize + j)?;
+                }
+                if self.quirks.memory {
+                    self.i = self.i.wrapping_add(x as u16 + 1);
+                }
+                self.pc += 2;
+            }
+            Invalid(opcode) => {
+                return Err(format!(
+                    "unknown opcode(0x{:X}000): 0x{:x}",
+                    opcode >> 12,
+                    opcode
+                ))
+            }
         }
         Ok(())
     }
 
+    // Maps an address to memory according to the memory policy
+    fn resolve(&mut self, addr: usize) -> Result<usize, String> {
+        if addr < MEMORY_SIZE {
+            return Ok(addr);
+        }
+        match self.memory_policy {
+            MemoryPolicy::Wrap => Ok(addr % MEMORY_SIZE),
+            MemoryPolicy::Error => Err(format!("memory access out of range: 0x{:x}", addr)),
+            MemoryPolicy::Trap => {
+                self.trap = Some(addr);
+                Err(format!("memory trap: 0x{:x}", addr))
+            }
+        }
+    }
+
+    fn load(&mut self, addr: usize) -> Result<u8, String> {
+        let addr = self.resolve(addr)?;
+        Ok(self.memory[addr])
+    }
+
+    fn store(&mut self, addr: usize, byte: u8) -> Result<(), String> {
+        let addr = self.resolve(addr)?;
+        self.memory[addr] = byte;
+        // Drop the instructions that start at or just before the written byte
+        let prev = (addr + MEMORY_SIZE - 1) % MEMORY_SIZE;
+        if self.cache[addr].is_some() || self.cache[prev].is_some() {
+            self.cache[addr] = None;
+            self.cache[prev] = None;
+            self.code_written(addr, addr);
+        }
+        Ok(())
+    }
+
+    // Remembers which code was overwritten, for the recompiler
+    fn code_written(&mut self, lo: usize, hi: usize) {
+        self.code_writes = Some(match self.code_writes {
+            Some((l, h)) => (l.min(lo), h.max(hi)),
+            None => (lo, hi),
+        });
+    }
+
     pub fn draw_flag(&mut self) -> bool {
         let f = self.draw_flag;
         self.draw_flag = false;
         f
     }
 
+    // Rows changed by 0x00E0 and 0xDXYN since the last call
+    pub fn take_dirty_rows(&mut self) -> DirtyRows {
+        self.gfx.take_dirty()
+    }
+
     pub fn dump(&self) {
         println!("memory:");
-        let begin = 0x200;
-        let end = 0x250;
+        let begin = self.load_address as usize;
+        let end = begin + 0x50;
         // print header
         print!("    |");
         for i in 0..16 {
@@ -473,6 +995,28 @@ impl Chip8 {
     }
 }
 
+fn check_key(k: u8) -> Result<(), String> {
+    if k as usize >= KEY_NUM {
+        return Err(format!("invalid key: 0x{:x}", k));
+    }
+    Ok(())
+}
+
+fn take<'a>(bytes: &mut &'a [u8], n: usize) -> Result<&'a [u8], String> {
+    if bytes.len() < n {
+        return Err("save state is truncated".to_string());
+    }
+    let (head, rest) = bytes.split_at(n);
+    *bytes = rest;
+    Ok(head)
+}
+
+impl Default for Chip8 {
+    fn default() -> Chip8 {
+        Chip8::new()
+    }
+}
+
 impl KeyBoard {
     pub fn new() -> KeyBoard {
         KeyBoard {
@@ -482,19 +1026,28 @@ impl KeyBoard {
     }
 }
 
+impl Default for KeyBoard {
+    fn default() -> KeyBoard {
+        KeyBoard::new()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
     #[test]
+    #[allow(clippy::bool_assert_comparison)]
     fn decode_execute_00e0() {
         let mut chip8 = Chip8::new();
         let k = KeyBoard::new();
         let opcode = 0x00E0;
-        chip8.gfx = [1; GFX_SIZE];
+        for y in 0..GFX_SIZE_ROW {
+            chip8.gfx.xor_row(y, u64::MAX);
+        }
 
         chip8.decode_execute(opcode, &k).unwrap();
-        assert_eq!([0; GFX_SIZE], chip8.gfx);
+        assert_eq!(Framebuffer::new(), chip8.gfx);
         assert_eq!(true, chip8.draw_flag);
         assert_eq!(0x202, chip8.pc);
     }
@@ -691,14 +1244,14 @@ mod tests {
         chip8.v[2] = 0x01;
         chip8.decode_execute(opcode, &k).unwrap();
         assert_eq!(0x00, chip8.v[1]);
-        assert_eq!(0, chip8.v[0xf]);
+        assert_eq!(1, chip8.v[0xf]);
         assert_eq!(0x202, chip8.pc);
 
         chip8.v[1] = 0x01;
         chip8.v[2] = 0x02;
         chip8.decode_execute(opcode, &k).unwrap();
         assert_eq!(0xff, chip8.v[1]);
-        assert_eq!(1, chip8.v[0xf]);
+        assert_eq!(0, chip8.v[0xf]);
         assert_eq!(0x204, chip8.pc);
     }
 
@@ -731,14 +1284,14 @@ mod tests {
         chip8.v[2] = 0x02;
         chip8.decode_execute(opcode, &k).unwrap();
         assert_eq!(0x01, chip8.v[1]);
-        assert_eq!(0, chip8.v[0xf]);
+        assert_eq!(1, chip8.v[0xf]);
         assert_eq!(0x202, chip8.pc);
 
         chip8.v[1] = 0x02;
         chip8.v[2] = 0x01;
         chip8.decode_execute(opcode, &k).unwrap();
         assert_eq!(0xff, chip8.v[1]);
-        assert_eq!(1, chip8.v[0xf]);
+        assert_eq!(0, chip8.v[0xf]);
         assert_eq!(0x204, chip8.pc);
     }
 
@@ -831,6 +1384,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::clone_on_copy)]
     fn decode_execute_dxyn() {
         let mut chip8 = Chip8::new();
         let k = KeyBoard::new();
@@ -840,40 +1394,139 @@ mod tests {
         chip8.i = 0x8 * 5;
         for y in 0..10 {
             for x in 3..10 {
-                chip8.gfx[y * GFX_SIZE_COL + x] = 1;
+                chip8.gfx.set_pixel(x, y, true);
             }
         }
         let mut des = chip8.gfx.clone();
-        des[2 * GFX_SIZE_COL + 1] = 1;
-        des[2 * GFX_SIZE_COL + 2] = 1;
-        des[2 * GFX_SIZE_COL + 3] = 0;
-        des[2 * GFX_SIZE_COL + 4] = 0;
+        des.set_pixel(1, 2, true);
+        des.set_pixel(2, 2, true);
+        des.set_pixel(3, 2, false);
+        des.set_pixel(4, 2, false);
         //
-        des[3 * GFX_SIZE_COL + 1] = 1;
-        des[3 * GFX_SIZE_COL + 4] = 0;
+        des.set_pixel(1, 3, true);
+        des.set_pixel(4, 3, false);
         //
-        des[4 * GFX_SIZE_COL + 1] = 1;
-        des[4 * GFX_SIZE_COL + 2] = 1;
-        des[4 * GFX_SIZE_COL + 3] = 0;
-        des[4 * GFX_SIZE_COL + 4] = 0;
+        des.set_pixel(1, 4, true);
+        des.set_pixel(2, 4, true);
+        des.set_pixel(3, 4, false);
+        des.set_pixel(4, 4, false);
         //
-        des[5 * GFX_SIZE_COL + 1] = 1;
-        des[5 * GFX_SIZE_COL + 4] = 0;
+        des.set_pixel(1, 5, true);
+        des.set_pixel(4, 5, false);
         //
-        des[6 * GFX_SIZE_COL + 1] = 1;
-        des[6 * GFX_SIZE_COL + 2] = 1;
-        des[6 * GFX_SIZE_COL + 3] = 0;
-        des[6 * GFX_SIZE_COL + 4] = 0;
+        des.set_pixel(1, 6, true);
+        des.set_pixel(2, 6, true);
+        des.set_pixel(3, 6, false);
+        des.set_pixel(4, 6, false);
 
         chip8.decode_execute(opcode, &k).unwrap();
         assert_eq!(des, chip8.gfx);
         assert_eq!(0x202, chip8.pc);
         for y in 0..10 {
-            let begin = y * GFX_SIZE_COL;
-            println!("{:?}", &chip8.gfx[begin..(begin + 10)]);
+            println!("{:010b}", chip8.gfx.row(y) >> (GFX_SIZE_COL - 10));
         }
     }
 
+    #[test]
+    fn dirty_rows() {
+        let mut chip8 = Chip8::new();
+        let k = KeyBoard::new();
+        chip8.v[1] = 60;
+        chip8.v[2] = 30;
+        // The 5 rows of the "0" sprite wrap around to the top
+        chip8.decode_execute(0xd125, &k).unwrap();
+        assert_eq!(
+            vec![0, 1, 2, 30, 31],
+            chip8.take_dirty_rows().iter().collect::<Vec<_>>()
+        );
+        assert!(chip8.take_dirty_rows().is_empty());
+
+        chip8.decode_execute(0x00e0, &k).unwrap();
+        assert_eq!(5, chip8.take_dirty_rows().iter().count());
+    }
+
+    #[test]
+    fn debugger_access() {
+        let mut chip8 = Chip8::new();
+        let k = KeyBoard::new();
+        chip8.load_rom(&[0x60, 0x01]).unwrap();
+        chip8.step(&k).unwrap();
+        let mut r = chip8.registers();
+        assert_eq!((1, 0x202), (r.v[0], r.pc));
+
+        // The cached instruction at 0x200 is replaced
+        r.pc = 0x200;
+        chip8.set_registers(r);
+        chip8.write_memory(0x201, &[0x07]).unwrap();
+        chip8.step(&k).unwrap();
+        assert_eq!(7, chip8.registers().v[0]);
+        assert_eq!(&[0x60, 0x07], &chip8.memory()[0x200..0x202]);
+        assert!(chip8.write_memory(MEMORY_SIZE - 1, &[0, 0]).is_err());
+        assert!(chip8.write_memory(usize::MAX, &[0]).is_err());
+        assert!(chip8.write_memory(0, &[]).is_ok());
+    }
+
+    #[test]
+    fn stack() {
+        let mut chip8 = Chip8::new();
+        let k = KeyBoard::new();
+        // Calls itself until the stack is full
+        chip8.load_rom(&[0x22, 0x00]).unwrap();
+        for n in 0..STACK_SIZE {
+            assert_eq!(n, chip8.stack().len());
+            chip8.step(&k).unwrap();
+        }
+        assert_eq!([0x200; STACK_SIZE], chip8.stack());
+        assert_eq!("stack overflow", chip8.step(&k).unwrap_err());
+
+        // A debugger can set any stack pointer
+        let mut r = chip8.registers();
+        r.sp = 20;
+        chip8.set_registers(r);
+        assert_eq!(STACK_SIZE, chip8.stack().len());
+        assert!(chip8.decode_execute(0x00ee, &k).is_err());
+    }
+
+    #[test]
+    fn quirk_profiles() {
+        for name in QUIRK_PROFILES {
+            assert_eq!(Some(name), Quirks::from_profile(name).unwrap().profile());
+        }
+        let quirks = Quirks {
+            jumping: true,
+            ..Quirks::default()
+        };
+        assert_eq!(None, quirks.profile());
+    }
+
+    #[test]
+    fn reset() {
+        let mut chip8 = Chip8::new();
+        let k = KeyBoard::new();
+        chip8
+            .load_rom(&[0x60, 0x05, 0xf0, 0x15, 0x22, 0x00])
+            .unwrap();
+        chip8.decode_execute(0xd015, &k).unwrap();
+        chip8.pc = LOAD_ADDRESS;
+        for _ in 0..3 {
+            chip8.step(&k).unwrap();
+        }
+        assert_eq!((5, 5, 1), (chip8.v[0], chip8.delay_timer, chip8.sp));
+
+        chip8.reset();
+        assert_eq!(
+            (0, 0, 0, 0),
+            (chip8.v[0], chip8.delay_timer, chip8.sp, chip8.cycles())
+        );
+        assert_eq!(LOAD_ADDRESS, chip8.pc);
+        assert_eq!(Framebuffer::new(), chip8.gfx);
+        assert_eq!(0x60, chip8.memory[0x200]);
+
+        chip8.clear_memory();
+        assert_eq!(0, chip8.memory[0x200]);
+        assert_eq!(CHIP8_FONTSET[..], chip8.memory[..80]);
+    }
+
     #[test]
     fn decode_execute_ex9e() {
         let mut chip8 = Chip8::new();
@@ -1031,4 +1684,203 @@ mod tests {
         assert_eq!(0x300, chip8.i);
         assert_eq!(0x202, chip8.pc);
     }
+
+    #[test]
+    fn memory_quirk_wraps_i() {
+        let mut chip8 = Chip8::new();
+        let k = KeyBoard::new();
+        chip8.set_quirks(Quirks::from_profile("chip8").unwrap());
+        chip8.i = 0xffff;
+        chip8.decode_execute(0xf055, &k).unwrap();
+        assert_eq!(0x0000, chip8.i);
+        chip8.i = 0xfffe;
+        chip8.decode_execute(0xf265, &k).unwrap();
+        assert_eq!(0x0001, chip8.i);
+    }
+
+    #[test]
+    fn emulate_cycle_self_jump() {
+        let mut chip8 = Chip8::new();
+        let k = KeyBoard::new();
+        chip8.memory[0x200] = 0x12; // 0x1200: jump to itself
+        chip8.memory[0x201] = 0x00;
+
+        assert_eq!(State::Halted, chip8.emulate_cycle(&k).unwrap());
+        assert_eq!(0x200, chip8.pc);
+    }
+
+    #[test]
+    fn run_frame_timer_wait() {
+        let mut chip8 = Chip8::new();
+        let k = KeyBoard::new();
+        let rom = [
+            0x60, 0x03, // 0x200: V0 = 3
+            0xf0, 0x15, // 0x202: delay timer = V0
+            0xf0, 0x07, // 0x204: V0 = delay timer
+            0x30, 0x00, // 0x206: skip if V0 == 0
+            0x12, 0x04, // 0x208: jump to 0x204
+            0x12, 0x0a, // 0x20a: jump to itself
+        ];
+        chip8.load_rom(&rom).unwrap();
+
+        for _ in 0..3 {
+            assert_eq!(State::Running, chip8.run_frame(&k, 10).unwrap());
+        }
+        assert_eq!(State::Halted, chip8.run_frame(&k, 10).unwrap());
+        assert_eq!(0x20a, chip8.pc);
+    }
+
+    #[test]
+    fn emulate_cycle_self_modifying() {
+        let mut chip8 = Chip8::new();
+        let k = KeyBoard::new();
+        let rom = [
+            0x70, 0x01, // 0x200: V0 += 1
+            0xa2, 0x01, // 0x202: I = 0x201
+            0x60, 0x05, // 0x204: V0 = 5
+            0xf0, 0x55, // 0x206: store V0 at 0x201, the 0x200 instruction becomes V0 += 5
+            0x12, 0x00, // 0x208: jump to 0x200
+        ];
+        chip8.load_rom(&rom).unwrap();
+
+        for _ in 0..6 {
+            chip8.emulate_cycle(&k).unwrap();
+        }
+        assert_eq!(10, chip8.v[0]);
+        assert_eq!(
+            Some((0x7005, Instruction::AddImm(0, 5))),
+            chip8.cache[0x200]
+        );
+    }
+
+    #[test]
+    fn emulate_cycle_idle_loop() {
+        let mut chip8 = Chip8::new();
+        let k = KeyBoard::new();
+        let rom = [
+            0x61, 0x05, // 0x200: V1 = 5
+            0x62, 0x00, // 0x202: V2 = 0
+            0x12, 0x02, // 0x204: jump to 0x202
+        ];
+        chip8.memory[0x200..0x206].copy_from_slice(&rom);
+
+        let mut states = Vec::new();
+        for _ in 0..5 {
+            states.push(chip8.emulate_cycle(&k).unwrap());
+        }
+        assert_eq!(State::Running, states[2]);
+        assert_eq!(State::Halted, states[4]);
+    }
+
+    #[test]
+    fn emulate_cycle_key_wait_loop() {
+        let mut chip8 = Chip8::new();
+        let k = KeyBoard::new();
+        let rom = [
+            0x61, 0x05, // 0x200: V1 = 5
+            0xe1, 0x9e, // 0x202: skip if key V1 is pressed
+            0x12, 0x02, // 0x204: jump to 0x202
+        ];
+        chip8.memory[0x200..0x206].copy_from_slice(&rom);
+
+        for _ in 0..10 {
+            assert_eq!(State::Running, chip8.emulate_cycle(&k).unwrap());
+        }
+    }
+
+    #[test]
+    fn memory_policy() {
+        let k = KeyBoard::new();
+        let opcode = 0xf155; // store V0 and V1 at 0xFFF and 0x1000
+
+        let mut chip8 = Chip8::new();
+        chip8.i = 0xfff;
+        chip8.v[0] = 0x12;
+        chip8.v[1] = 0x34;
+        chip8.decode_execute(opcode, &k).unwrap();
+        assert_eq!(0x12, chip8.memory[0xfff]);
+        assert_eq!(0x34, chip8.memory[0x000]);
+
+        let mut chip8 = Chip8::new();
+        chip8.set_memory_policy(MemoryPolicy::Error);
+        chip8.i = 0xfff;
+        assert!(chip8.decode_execute(opcode, &k).is_err());
+
+        let mut chip8 = Chip8::new();
+        chip8.set_memory_policy(MemoryPolicy::Trap);
+        chip8.i = 0xfff;
+        chip8.memory[0x200] = 0xf1;
+        chip8.memory[0x201] = 0x55;
+        assert_eq!(State::Trapped(0x1000), chip8.step(&k).unwrap());
+        assert_eq!(0x200, chip8.pc);
+
+        // A sprite running past the end of memory draws nothing
+        let mut chip8 = Chip8::new();
+        chip8.set_memory_policy(MemoryPolicy::Error);
+        chip8.i = 0xffe;
+        chip8.memory[0xffe] = 0xff;
+        chip8.v[0xf] = 7;
+        assert!(chip8.decode_execute(0xd003, &k).is_err());
+        assert_eq!(Framebuffer::new(), chip8.gfx);
+        assert_eq!(7, chip8.v[0xf]);
+    }
+
+    #[test]
+    fn save_state() {
+        let mut chip8 = Chip8::new();
+        let k = KeyBoard::new();
+        chip8
+            .load_rom(&[0x60, 0x05, 0xf0, 0x15, 0xa0, 0x00, 0x22, 0x0a])
+            .unwrap();
+        chip8.decode_execute(0xd015, &k).unwrap();
+        chip8.pc = LOAD_ADDRESS;
+        for _ in 0..4 {
+            chip8.step(&k).unwrap();
+        }
+        let state = chip8.save_state();
+
+        let mut restored = Chip8::new();
+        restored.load_state(&state).unwrap();
+        assert_eq!(chip8.registers(), restored.registers());
+        assert_eq!(chip8.memory(), restored.memory());
+        assert_eq!(chip8.stack(), restored.stack());
+        assert_eq!(chip8.gfx, restored.gfx);
+        assert_eq!(chip8.cycles(), restored.cycles());
+
+        // A bad state changes nothing
+        let mut other = Chip8::new();
+        assert!(other.load_state(&state[..state.len() - 1]).is_err());
+        assert!(other
+            .load_state(&[state.as_slice(), &[0]].concat())
+            .is_err());
+        assert!(other.load_state(b"C8T1").is_err());
+        assert_eq!(0, other.memory()[0x200]);
+    }
+
+    #[test]
+    fn load_rom() {
+        let mut chip8 = Chip8::new();
+        chip8.load_rom(&[0x12, 0x34]).unwrap();
+        assert_eq!([0x12, 0x34], chip8.memory[0x200..0x202]);
+        assert_eq!(0x200, chip8.pc);
+
+        let rom = [0; MEMORY_SIZE - 0x200 + 1];
+        let e = chip8.load_rom(&rom).unwrap_err();
+        assert_eq!(MEMORY_SIZE - 0x200 + 1, e.size);
+        assert_eq!(MEMORY_SIZE - 0x200, e.max);
+
+        let mut chip8 = Chip8::new();
+        chip8.set_load_address(0x600).unwrap();
+        chip8.load_rom(&[0x12, 0x34]).unwrap();
+        assert_eq!([0x12, 0x34], chip8.memory[0x600..0x602]);
+        assert_eq!(0x600, chip8.pc);
+        assert!(chip8.load_rom(&[0; MEMORY_SIZE - 0x600 + 1]).is_err());
+
+        // An address past the end of memory is refused and changes nothing
+        assert!(chip8.set_load_address(0x1001).is_err());
+        assert!(chip8.set_load_address(MEMORY_SIZE as u16).is_err());
+        assert_eq!(0x600, chip8.pc);
+        chip8.load_rom(&[0x12]).unwrap();
+        assert_eq!(0x12, chip8.memory[0x600]);
+    }
 }
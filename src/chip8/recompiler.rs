@@ -0,0 +1,318 @@
+use super::{Chip8, KeyBoard, State, MEMORY_SIZE};
+use crate::instruction::Instruction;
+
+// An instruction compiled into a closure
+type Op = Box<dyn Fn(&mut Chip8, &KeyBoard) -> Result<(), String>>;
+
+// Straight-line code, only the last instruction may change the
+// program counter other than by moving to the next instruction
+struct Block {
+    // Address after the last instruction
+    end: usize,
+    // Opcode and compiled code of each instruction
+    ops: Vec<(u16, Op)>,
+}
+
+// Runs a Chip8 by compiling the code it reaches into basic blocks.
+// Blocks are dropped when the code they were compiled from is written,
+// so self-modifying programs behave the same as in the interpreter.
+pub struct Recompiler {
+    // Compiled blocks by start address
+    blocks: Vec<Option<Block>>,
+}
+
+impl Recompiler {
+    pub fn new() -> Recompiler {
+        Recompiler {
+            blocks: (0..MEMORY_SIZE).map(|_| None).collect(),
+        }
+    }
+
+    // Same as `Chip8::run_frame`
+    pub fn run_frame(
+        &mut self,
+        chip8: &mut Chip8,
+        kb: &KeyBoard,
+        instructions: usize,
+    ) -> Result<State, String> {
+        let state = self.run(chip8, kb, instructions)?;
+        chip8.update_timers();
+        Ok(state)
+    }
+
+    // Executes up to `instructions` instructions, stopping early when the program stops
+    pub fn run(
+        &mut self,
+        chip8: &mut Chip8,
+        kb: &KeyBoard,
+        instructions: usize,
+    ) -> Result<State, String> {
+        // Memory may have been written outside this run, e.g. by loading a ROM
+        self.invalidate(chip8);
+        let mut left = instructions;
+        while left > 0 {
+            // The interpreter handles the program counter at the end of or beyond memory
+            let start = chip8.pc as usize;
+            if start + 1 >= MEMORY_SIZE {
+                left -= 1;
+                match chip8.step(kb)? {
+                    State::Running => continue,
+                    state => return Ok(state),
+                }
+            }
+
+            if self.blocks[start].is_none() {
+                self.blocks[start] = Some(compile(chip8, start)?);
+            }
+            let block = self.blocks[start].as_ref().unwrap();
+            let mut result = Ok(State::Running);
+            for (opcode, op) in &block.ops {
+                if left == 0 {
+                    break;
+                }
+                left -= 1;
+                let pc = chip8.pc;
+                let r = op(chip8, kb);
+                result = chip8.complete(pc, *opcode, r);
+                if !matches!(result, Ok(State::Running)) {
+                    break;
+                }
+            }
+            self.invalidate(chip8);
+            if !matches!(result, Ok(State::Running)) {
+                return result;
+            }
+        }
+        Ok(State::Running)
+    }
+
+    // Drops the blocks that contain code written since the last call
+    fn invalidate(&mut self, chip8: &mut Chip8) {
+        let Some((lo, hi)) = chip8.code_writes.take() else {
+            return;
+        };
+        for (start, slot) in self.blocks.iter_mut().enumerate() {
+            if slot.as_ref().is_some_and(|b| start <= hi && lo < b.end) {
+                *slot = None;
+            }
+        }
+    }
+}
+
+impl Default for Recompiler {
+    fn default() -> Recompiler {
+        Recompiler::new()
+    }
+}
+
+fn compile(chip8: &mut Chip8, start: usize) -> Result<Block, String> {
+    let mut ops = Vec::new();
+    let mut addr = start;
+    while addr + 1 < MEMORY_SIZE {
+        let (opcode, instruction) = chip8.decode_at(addr)?;
+        ops.push((opcode, compile_op(instruction)));
+        addr += 2;
+        if ends_block(instruction) {
+            break;
+        }
+    }
+    Ok(Block { end: addr, ops })
+}
+
+// Instructions that jump, skip, wait, draw or write memory
+fn ends_block(instruction: Instruction) -> bool {
+    use Instruction::*;
+    matches!(
+        instruction,
+        Ret | Jp(_)
+            | Call(_)
+            | SeImm(..)
+            | SneImm(..)
+            | SeReg(..)
+            | SneReg(..)
+            | JpV0(..)
+            | Drw(..)
+            | Skp(_)
+            | Sknp(_)
+            | LdVxK(_)
+            | LdB(_)
+            | Store(_)
+            | Invalid(_)
+    )
+}
+
+// The common instructions are compiled with their operands in place,
+// the rest run through the interpreter
+fn compile_op(instruction: Instruction) -> Op {
+    use Instruction::*;
+    match instruction {
+        Jp(nnn) => Box::new(move |c, _| {
+            c.pc = nnn;
+            Ok(())
+        }),
+        SeImm(x, nn) => {
+            let x = x as usize;
+            Box::new(move |c, _| {
+                c.pc += if c.v[x] == nn { 4 } else { 2 };
+                Ok(())
+            })
+        }
+        SneImm(x, nn) => {
+            let x = x as usize;
+            Box::new(move |c, _| {
+                c.pc += if c.v[x] != nn { 4 } else { 2 };
+                Ok(())
+            })
+        }
+        SeReg(x, y) => {
+            let (x, y) = (x as usize, y as usize);
+            Box::new(move |c, _| {
+                c.pc += if c.v[x] == c.v[y] { 4 } else { 2 };
+                Ok(())
+            })
+        }
+        SneReg(x, y) => {
+            let (x, y) = (x as usize, y as usize);
+            Box::new(move |c, _| {
+                c.pc += if c.v[x] != c.v[y] { 4 } else { 2 };
+                Ok(())
+            })
+        }
+        LdImm(x, nn) => {
+            let x = x as usize;
+            Box::new(move |c, _| {
+                c.v[x] = nn;
+                c.pc += 2;
+                Ok(())
+            })
+        }
+        AddImm(x, nn) => {
+            let x = x as usize;
+            Box::new(move |c, _| {
+                c.v[x] = c.v[x].wrapping_add(nn);
+                c.pc += 2;
+                Ok(())
+            })
+        }
+        LdReg(x, y) => {
+            let (x, y) = (x as usize, y as usize);
+            Box::new(move |c, _| {
+                c.v[x] = c.v[y];
+                c.pc += 2;
+                Ok(())
+            })
+        }
+        LdI(nnn) => Box::new(move |c, _| {
+            c.i = nnn;
+            c.pc += 2;
+            Ok(())
+        }),
+        _ => Box::new(move |c, kb| c.execute(instruction, kb)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chip8::{MemoryPolicy, Quirks, QUIRK_PROFILES};
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+    use std::fs;
+
+    fn assert_same(a: &Chip8, b: &Chip8, context: &str) {
+        assert_eq!(a.cycles, b.cycles, "cycles {}", context);
+        assert_eq!(a.pc, b.pc, "pc {}", context);
+        assert_eq!(a.v, b.v, "v {}", context);
+        assert_eq!(a.i, b.i, "i {}", context);
+        assert_eq!(a.sp, b.sp, "sp {}", context);
+        assert_eq!(a.stack, b.stack, "stack {}", context);
+        assert_eq!(a.delay_timer, b.delay_timer, "delay timer {}", context);
+        assert_eq!(a.sound_timer, b.sound_timer, "sound timer {}", context);
+        assert!(a.memory == b.memory, "memory {}", context);
+        assert!(a.gfx == b.gfx, "gfx {}", context);
+    }
+
+    // Runs the ROM in both engines with frames of varying length and
+    // compares the machines after every frame
+    fn lockstep(rom: &[u8], quirks: Quirks, policy: MemoryPolicy, kb: &KeyBoard, seed: u64) {
+        let mut interpreter = Chip8::new();
+        interpreter.set_quirks(quirks);
+        interpreter.set_memory_policy(policy);
+        interpreter.set_seed(seed);
+        if interpreter.load_rom(rom).is_err() {
+            return;
+        }
+        let mut compiled = interpreter.clone();
+        let mut recompiler = Recompiler::new();
+        let mut rng = StdRng::seed_from_u64(seed);
+        for frame in 0..200 {
+            let n = rng.gen_range(1..40);
+            let expected = interpreter.run_frame(kb, n);
+            let actual = recompiler.run_frame(&mut compiled, kb, n);
+            let context = format!("in frame {} of {:02x?}", frame, rom);
+            assert_eq!(expected, actual, "{}", context);
+            assert_same(&interpreter, &compiled, &context);
+            if expected != Ok(State::Running) {
+                break;
+            }
+        }
+    }
+
+    #[test]
+    fn test_roms() {
+        let kb = KeyBoard::new();
+        for entry in fs::read_dir("tests/roms").unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().is_some_and(|e| e == "ch8") {
+                let rom = fs::read(&path).unwrap();
+                for profile in QUIRK_PROFILES {
+                    let quirks = Quirks::from_profile(profile).unwrap();
+                    lockstep(&rom, quirks, MemoryPolicy::Wrap, &kb, 0);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn self_modifying() {
+        let kb = KeyBoard::new();
+        let rom = [
+            0x70, 0x01, // 0x200: V0 += 1
+            0xa2, 0x01, // 0x202: I = 0x201
+            0xf0, 0x55, // 0x204: store V0 at 0x201, the 0x200 instruction becomes V0 += V0
+            0x12, 0x00, // 0x206: jump to 0x200
+        ];
+        lockstep(&rom, Quirks::default(), MemoryPolicy::Wrap, &kb, 0);
+
+        let mut chip8 = Chip8::new();
+        chip8.load_rom(&rom).unwrap();
+        let mut recompiler = Recompiler::new();
+        recompiler.run(&mut chip8, &kb, 10).unwrap();
+        // 1, 1 + 1, then 2 + 2 with the value written by the second store
+        assert_eq!(4, chip8.v[0]);
+
+        // Blocks compiled from the old ROM are not run after loading another
+        chip8.reset();
+        chip8.clear_memory();
+        chip8.load_rom(&[0x60, 0x07, 0x12, 0x02]).unwrap();
+        recompiler.run(&mut chip8, &kb, 10).unwrap();
+        assert_eq!(7, chip8.v[0]);
+    }
+
+    #[test]
+    fn random_roms() {
+        let mut rng = StdRng::seed_from_u64(36);
+        for seed in 0..300 {
+            let len = rng.gen_range(2..=256);
+            let rom: Vec<u8> = (0..len).map(|_| rng.gen()).collect();
+            let mut kb = KeyBoard::new();
+            kb.key[rng.gen_range(0..16)] = 1;
+            for policy in [MemoryPolicy::Wrap, MemoryPolicy::Error, MemoryPolicy::Trap] {
+                for profile in QUIRK_PROFILES {
+                    let quirks = Quirks::from_profile(profile).unwrap();
+                    lockstep(&rom, quirks, policy, &kb, seed);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,378 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::env;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use toml_edit::{DocumentMut, Item, TableLike};
+
+// ~/.config/rs-chip-8/config.toml
+//
+//     ipf = 10
+//     quirks = "default"
+//     palette = "gameboy"
+//     scale = 10
+//     save_dir = "/home/me/chip8"   # save states, ~/.config/rs-chip-8/saves if unset
+//
+//     [audio]
+//     enabled = true
+//     volume = 0.25
+//
+//     [display]
+//     persistence = "phosphor"   # off, antiflicker or phosphor
+//     frames = 4
+//     decay = 0.5
+//     filters = ["scanlines", "vignette"]
+//
+//     [keymap]
+//     5 = "Up"
+//
+//     # Kept up to date by the ROM browser
+//     [launcher]
+//     dir = "/home/me/chip8/roms"
+//     recent = ["/home/me/chip8/roms/pong.ch8"]
+//     favourites = ["/home/me/chip8/roms/tetris.ch8"]
+//
+//     # Matched by file name or SHA-1
+//     [roms."pong.ch8"]
+//     ipf = 7
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    #[serde(flatten)]
+    pub settings: Settings,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub save_dir: Option<PathBuf>,
+    pub audio: Audio,
+    pub display: DisplayOptions,
+    // CHIP-8 key in hex -> SDL key name
+    pub keymap: BTreeMap<String, String>,
+    pub launcher: LauncherOptions,
+    pub roms: BTreeMap<String, Settings>,
+}
+
+// Settings that can also be set per ROM
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ipf: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub quirks: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub palette: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scale: Option<u32>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Audio {
+    pub enabled: bool,
+    // 0.0 to 1.0
+    pub volume: f32,
+}
+
+impl Default for Audio {
+    fn default() -> Audio {
+        Audio {
+            enabled: true,
+            volume: 0.25,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DisplayOptions {
+    pub persistence: String,
+    // Frames a phosphor pixel stays visible and how much it fades each frame
+    pub frames: usize,
+    pub decay: f32,
+    // Applied in order: scanlines, grid, rounded, bloom, vignette
+    pub filters: Vec<String>,
+}
+
+impl Default for DisplayOptions {
+    fn default() -> DisplayOptions {
+        DisplayOptions {
+            persistence: "off".to_string(),
+            frames: 4,
+            decay: 0.5,
+            filters: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LauncherOptions {
+    // Listed when no ROM is given, the current directory if not set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dir: Option<PathBuf>,
+    // Most recent first
+    pub recent: Vec<PathBuf>,
+    pub favourites: Vec<PathBuf>,
+}
+
+const MAX_RECENT: usize = 10;
+
+impl LauncherOptions {
+    // Moves the ROM to the front of the recent list, returns false if it already was
+    pub fn add_recent(&mut self, rom: &Path) -> bool {
+        if self.recent.first().is_some_and(|r| r == rom) {
+            return false;
+        }
+        self.recent.retain(|r| r != rom);
+        self.recent.insert(0, rom.to_path_buf());
+        self.recent.truncate(MAX_RECENT);
+        true
+    }
+
+    pub fn is_favourite(&self, rom: &Path) -> bool {
+        self.favourites.iter().any(|f| f == rom)
+    }
+
+    pub fn toggle_favourite(&mut self, rom: &Path) {
+        if self.is_favourite(rom) {
+            self.favourites.retain(|f| f != rom);
+        } else {
+            self.favourites.push(rom.to_path_buf());
+        }
+    }
+}
+
+impl Settings {
+    // Values set in `other` replace the values in `self`
+    pub fn merge(&mut self, other: &Settings) {
+        if other.ipf.is_some() {
+            self.ipf = other.ipf;
+        }
+        if other.quirks.is_some() {
+            self.quirks = other.quirks.clone();
+        }
+        if other.palette.is_some() {
+            self.palette = other.palette.clone();
+        }
+        if other.scale.is_some() {
+            self.scale = other.scale;
+        }
+    }
+}
+
+impl Config {
+    pub fn path() -> Option<PathBuf> {
+        config_dir().map(|dir| dir.join("config.toml"))
+    }
+
+    // A missing file is an empty config
+    pub fn load() -> Result<Config, Box<dyn Error>> {
+        match Config::path() {
+            Some(path) if path.exists() => Config::read(&path),
+            _ => Ok(Config::default()),
+        }
+    }
+
+    pub fn read(path: &Path) -> Result<Config, Box<dyn Error>> {
+        let text = fs::read_to_string(path)?;
+        toml::from_str(&text).map_err(|e| format!("{}: {}", path.display(), e).into())
+    }
+
+    pub fn save(&self) -> Result<(), Box<dyn Error>> {
+        let path = Config::path().ok_or("no config directory")?;
+        self.write(&path)
+    }
+
+    // Only the keys that changed since the file was read are rewritten, so
+    // comments, key order and keys this version doesn't know about stay
+    pub fn write(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e.into()),
+        };
+        let invalid = |e: &dyn Error| format!("{}: {}", path.display(), e);
+        let mut doc: DocumentMut = text.parse().map_err(|e| invalid(&e))?;
+        let old: Config = toml::from_str(&text).map_err(|e| invalid(&e))?;
+        let old: DocumentMut = toml::to_string(&old)?.parse()?;
+        let new: DocumentMut = toml::to_string(self)?.parse()?;
+        update(doc.as_table_mut(), old.as_table(), new.as_table());
+        fs::write(path, doc.to_string())?;
+        Ok(())
+    }
+
+    // Where save states go
+    pub fn save_dir(&self) -> Option<PathBuf> {
+        match &self.save_dir {
+            Some(dir) => Some(dir.clone()),
+            None => config_dir().map(|dir| dir.join("saves")),
+        }
+    }
+
+    // Overrides for a ROM, the section for its SHA-1 wins over the one for its file name
+    pub fn rom_settings(&self, filename: &str, sha1: &str) -> Settings {
+        let mut settings = Settings::default();
+        let name = Path::new(filename).file_name().and_then(|n| n.to_str());
+        if let Some(s) = name.and_then(|n| self.roms.get(n)) {
+            settings.merge(s);
+        }
+        if let Some(s) = self.roms.get(sha1) {
+            settings.merge(s);
+        }
+        settings
+    }
+}
+
+// Applies the differences between old and new to the file's table
+fn update(file: &mut dyn TableLike, old: &dyn TableLike, new: &dyn TableLike) {
+    for (key, item) in new.iter() {
+        let before = old.get(key);
+        if before.map(|b| b.to_string()) == Some(item.to_string()) {
+            continue;
+        }
+        let tables = (before.and_then(Item::as_table_like), item.as_table_like());
+        match (file.get_mut(key), tables) {
+            (Some(entry), (Some(before), Some(table))) if entry.is_table_like() => {
+                update(entry.as_table_like_mut().unwrap(), before, table)
+            }
+            // Keeps the comment after the value
+            (Some(Item::Value(value)), _) if item.is_value() => {
+                let decor = value.decor().clone();
+                *value = item.as_value().unwrap().clone();
+                *value.decor_mut() = decor;
+            }
+            _ => {
+                file.insert(key, item.clone());
+            }
+        }
+    }
+    for (key, _) in old.iter() {
+        if new.get(key).is_none() {
+            file.remove(key);
+        }
+    }
+}
+
+// $XDG_CONFIG_HOME/rs-chip-8 or ~/.config/rs-chip-8
+pub fn config_dir() -> Option<PathBuf> {
+    let base = match env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(env::var_os("HOME")?).join(".config"),
+    };
+    Some(base.join("rs-chip-8"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOML: &str = r#"
+        ipf = 12
+        palette = "amber"
+
+        [audio]
+        volume = 0.5
+
+        [keymap]
+        5 = "Up"
+
+        [launcher]
+        recent = ["/roms/pong.ch8"]
+
+        [display]
+        persistence = "phosphor"
+        filters = ["grid", "bloom"]
+
+        [roms."pong.ch8"]
+        ipf = 7
+        quirks = "chip8"
+
+        [roms.a9993e364706816aba3e25717850c26c9cd0d89d]
+        ipf = 20
+    "#;
+
+    #[test]
+    fn parse() {
+        let config: Config = toml::from_str(TOML).unwrap();
+        assert_eq!(Some(12), config.settings.ipf);
+        assert_eq!(Some("amber".to_string()), config.settings.palette);
+        assert_eq!(None, config.settings.scale);
+        assert!(config.audio.enabled);
+        assert_eq!(0.5, config.audio.volume);
+        assert_eq!(Some(&"Up".to_string()), config.keymap.get("5"));
+        assert_eq!("phosphor", config.display.persistence);
+        assert_eq!(4, config.display.frames);
+        assert_eq!(vec!["grid", "bloom"], config.display.filters);
+
+        let s = config.rom_settings("roms/pong.ch8", "0000");
+        assert_eq!(Some(7), s.ipf);
+        assert_eq!(Some("chip8".to_string()), s.quirks);
+        let s = config.rom_settings("pong.ch8", "a9993e364706816aba3e25717850c26c9cd0d89d");
+        assert_eq!(Some(20), s.ipf);
+        assert_eq!(Some("chip8".to_string()), s.quirks);
+        assert_eq!(
+            Settings::default(),
+            config.rom_settings("tetris.ch8", "0000")
+        );
+    }
+
+    #[test]
+    fn round_trip() {
+        let config: Config = toml::from_str(TOML).unwrap();
+        let text = toml::to_string(&config).unwrap();
+        assert_eq!(config, toml::from_str(&text).unwrap());
+        assert_eq!(Config::default(), toml::from_str("").unwrap());
+        let config: Config = toml::from_str("save_dir = \"/home/me/chip8\"").unwrap();
+        assert_eq!(Some(PathBuf::from("/home/me/chip8")), config.save_dir());
+        let text = toml::to_string(&config).unwrap();
+        assert_eq!(config, toml::from_str(&text).unwrap());
+    }
+
+    #[test]
+    fn write() {
+        let path = env::temp_dir().join(format!("rs-chip-8-config-{}.toml", std::process::id()));
+        let text = "# my settings\nscale = 8 # big\nunknown = 1\n\n[audio]\nvolume = 0.5\n";
+        fs::write(&path, text).unwrap();
+        let mut config = Config::read(&path).unwrap();
+        config.settings.scale = Some(12);
+        config.settings.palette = Some("amber".to_string());
+        config.audio.volume = 0.75;
+        config.write(&path).unwrap();
+        let written = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(config, toml::from_str(&written).unwrap());
+        assert!(written.starts_with("# my settings\nscale = 12 # big\nunknown = 1\n"));
+        assert!(written.contains("[audio]\nvolume = 0.75\n"));
+        assert!(!written.contains("[display]"));
+
+        // A new file has every key
+        let path = env::temp_dir().join(format!("rs-chip-8-new-{}.toml", std::process::id()));
+        config.write(&path).unwrap();
+        let written = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(config, toml::from_str(&written).unwrap());
+    }
+
+    #[test]
+    fn launcher() {
+        let mut config: Config = toml::from_str(TOML).unwrap();
+        let launcher = &mut config.launcher;
+        assert!(!launcher.add_recent(Path::new("/roms/pong.ch8")));
+        for n in 0..MAX_RECENT {
+            assert!(launcher.add_recent(Path::new(&format!("/roms/{}.ch8", n))));
+        }
+        assert!(launcher.add_recent(Path::new("/roms/3.ch8")));
+        assert_eq!(MAX_RECENT, launcher.recent.len());
+        assert_eq!(Path::new("/roms/3.ch8"), launcher.recent[0]);
+        assert_eq!(Path::new("/roms/9.ch8"), launcher.recent[1]);
+
+        let tetris = Path::new("/roms/tetris.ch8");
+        launcher.toggle_favourite(tetris);
+        assert!(launcher.is_favourite(tetris));
+        launcher.toggle_favourite(tetris);
+        assert!(launcher.favourites.is_empty());
+    }
+}
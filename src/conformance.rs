@@ -0,0 +1,168 @@
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+
+use crate::chip8::{
+    Chip8, KeyBoard, Quirks, GFX_SIZE, GFX_SIZE_COL, GFX_SIZE_ROW, KEY_NUM, QUIRK_PROFILES,
+};
+use crate::framebuffer::Framebuffer;
+
+// Every test ROM runs for the same number of frames
+pub const FRAMES: usize = 180;
+pub const INSTRUCTIONS_PER_FRAME: usize = 15;
+
+pub struct Report {
+    pub rom: String,
+    pub profile: String,
+    pub passed: bool,
+    // Rendered screen when it does not match the golden image
+    pub actual: Option<String>,
+}
+
+// Runs the ROM without a window, with `keys` held down throughout, and
+// returns the resulting screen
+pub fn run(
+    filename: &str,
+    quirks: Quirks,
+    keys: &[u8],
+    frames: usize,
+) -> Result<Framebuffer, Box<dyn Error>> {
+    let mut chip8 = Chip8::new();
+    let mut kb = KeyBoard::new();
+    for &k in keys {
+        kb.key[k as usize] = 1;
+    }
+    chip8.set_quirks(quirks);
+    chip8.load_game(filename)?;
+    for _ in 0..frames {
+        chip8.run_frame(&kb, INSTRUCTIONS_PER_FRAME)?;
+    }
+    Ok(chip8.gfx)
+}
+
+// Golden images are text, one line per row, '#' for set pixels and '.' for unset pixels
+pub fn render(gfx: &Framebuffer) -> String {
+    let mut s = String::with_capacity(GFX_SIZE + GFX_SIZE_ROW);
+    for y in 0..GFX_SIZE_ROW {
+        for x in 0..GFX_SIZE_COL {
+            s.push(if gfx.pixel(x, y) { '#' } else { '.' });
+        }
+        s.push('\n');
+    }
+    s
+}
+
+pub fn parse(text: &str) -> Result<Framebuffer, String> {
+    let mut gfx = Framebuffer::new();
+    let mut n = 0;
+    for c in text.chars().filter(|c| !c.is_whitespace()) {
+        if n >= GFX_SIZE {
+            return Err("golden image is too large".to_string());
+        }
+        let on = match c {
+            '#' => true,
+            '.' => false,
+            _ => return Err(format!("invalid pixel in golden image: {:?}", c)),
+        };
+        gfx.set_pixel(n % GFX_SIZE_COL, n / GFX_SIZE_COL, on);
+        n += 1;
+    }
+    if n != GFX_SIZE {
+        return Err(format!(
+            "golden image has {} pixels, expected {}",
+            n, GFX_SIZE
+        ));
+    }
+    Ok(gfx)
+}
+
+// Keys held down while a ROM runs, as hex digits in `<rom>.keys`, e.g. "1 5 a f"
+pub fn parse_keys(text: &str) -> Result<Vec<u8>, String> {
+    text.split_whitespace()
+        .map(|k| match u8::from_str_radix(k, 16) {
+            Ok(k) if (k as usize) < KEY_NUM => Ok(k),
+            _ => Err(format!("invalid key: {}", k)),
+        })
+        .collect()
+}
+
+// Runs every `<rom>.ch8` in `dir` against each `<rom>.<profile>.txt` golden image.
+// With `bless` set, the golden images are rewritten from the actual screens.
+pub fn run_suite(dir: &Path, bless: bool) -> Result<Vec<Report>, Box<dyn Error>> {
+    let mut roms: Vec<_> = fs::read_dir(dir)?
+        .filter_map(|e| e.ok().map(|e| e.path()))
+        .filter(|p| p.extension().is_some_and(|e| e == "ch8"))
+        .collect();
+    roms.sort();
+
+    let mut reports = Vec::new();
+    for rom in roms {
+        let name = rom.file_stem().unwrap().to_string_lossy().to_string();
+        let keys = match fs::read_to_string(rom.with_extension("keys")) {
+            Ok(text) => parse_keys(&text)?,
+            Err(_) => Vec::new(),
+        };
+        for profile in QUIRK_PROFILES {
+            let golden = dir.join(format!("{}.{}.txt", name, profile));
+            if !golden.exists() && !bless {
+                continue;
+            }
+            let quirks = Quirks::from_profile(profile).unwrap();
+            let gfx = run(&rom.to_string_lossy(), quirks, &keys, FRAMES)?;
+            if bless {
+                fs::write(&golden, render(&gfx))?;
+            }
+            let expected = parse(&fs::read_to_string(&golden)?)?;
+            let passed = expected == gfx;
+            reports.push(Report {
+                rom: name.clone(),
+                profile: profile.to_string(),
+                passed,
+                actual: if passed { None } else { Some(render(&gfx)) },
+            });
+        }
+    }
+    Ok(reports)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn render_parse() {
+        let mut gfx = Framebuffer::new();
+        gfx.set_pixel(0, 0, true);
+        gfx.set_pixel(GFX_SIZE_COL - 1, GFX_SIZE_ROW - 1, true);
+        let text = render(&gfx);
+        assert!(text.starts_with("#."));
+        assert_eq!(gfx, parse(&text).unwrap());
+        assert!(parse("#.").is_err());
+
+        assert_eq!(vec![1, 5, 0xa], parse_keys("1 5\na\n").unwrap());
+        assert!(parse_keys("10").is_err());
+    }
+
+    // The ROMs in tests/roms are small ones written for this project, not the
+    // upstream test suites. Set CHIP8_BLESS=1 to regenerate the golden images.
+    #[test]
+    fn test_roms() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/roms");
+        let bless = env::var_os("CHIP8_BLESS").is_some();
+        let reports = run_suite(&dir, bless).unwrap();
+        assert!(!reports.is_empty());
+        for r in &reports {
+            println!(
+                "{} [{}]: {}",
+                r.rom,
+                r.profile,
+                if r.passed { "pass" } else { "FAIL" }
+            );
+            if let Some(actual) = &r.actual {
+                println!("{}", actual);
+            }
+        }
+        assert!(reports.iter().all(|r| r.passed));
+    }
+}
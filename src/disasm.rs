@@ -0,0 +1,111 @@
+use std::fmt::Write;
+
+// Returns the mnemonic of the opcode, or a DW directive if it is not an instruction
+pub fn disassemble(opcode: u16) -> String {
+    let x = (opcode & 0x0F00) >> 8;
+    let y = (opcode & 0x00F0) >> 4;
+    let n = opcode & 0x000F;
+    let nn = opcode & 0x00FF;
+    let nnn = opcode & 0x0FFF;
+    match opcode & 0xF000 {
+        0x0000 => match opcode {
+            0x00E0 => "CLS".to_string(),
+            0x00EE => "RET".to_string(),
+            _ => format!("SYS 0x{:03x}", nnn),
+        },
+        0x1000 => format!("JP 0x{:03x}", nnn),
+        0x2000 => format!("CALL 0x{:03x}", nnn),
+        0x3000 => format!("SE V{:X}, 0x{:02x}", x, nn),
+        0x4000 => format!("SNE V{:X}, 0x{:02x}", x, nn),
+        0x5000 if n == 0 => format!("SE V{:X}, V{:X}", x, y),
+        0x6000 => format!("LD V{:X}, 0x{:02x}", x, nn),
+        0x7000 => format!("ADD V{:X}, 0x{:02x}", x, nn),
+        0x8000 => {
+            let mnemonic = match n {
+                0x0 => "LD",
+                0x1 => "OR",
+                0x2 => "AND",
+                0x3 => "XOR",
+                0x4 => "ADD",
+                0x5 => "SUB",
+                0x6 => "SHR",
+                0x7 => "SUBN",
+                0xE => "SHL",
+                _ => return data(opcode),
+            };
+            format!("{} V{:X}, V{:X}", mnemonic, x, y)
+        }
+        0x9000 if n == 0 => format!("SNE V{:X}, V{:X}", x, y),
+        0xA000 => format!("LD I, 0x{:03x}", nnn),
+        0xB000 => format!("JP V0, 0x{:03x}", nnn),
+        0xC000 => format!("RND V{:X}, 0x{:02x}", x, nn),
+        0xD000 => format!("DRW V{:X}, V{:X}, {}", x, y, n),
+        0xE000 => match nn {
+            0x9E => format!("SKP V{:X}", x),
+            0xA1 => format!("SKNP V{:X}", x),
+            _ => data(opcode),
+        },
+        0xF000 => match nn {
+            0x07 => format!("LD V{:X}, DT", x),
+            0x0A => format!("LD V{:X}, K", x),
+            0x15 => format!("LD DT, V{:X}", x),
+            0x18 => format!("LD ST, V{:X}", x),
+            0x1E => format!("ADD I, V{:X}", x),
+            0x29 => format!("LD F, V{:X}", x),
+            0x33 => format!("LD B, V{:X}", x),
+            0x55 => format!("LD [I], V{:X}", x),
+            0x65 => format!("LD V{:X}, [I]", x),
+            _ => data(opcode),
+        },
+        _ => data(opcode),
+    }
+}
+
+fn data(opcode: u16) -> String {
+    format!("DW 0x{:04x}", opcode)
+}
+
+// Disassembles a whole ROM into source the assembler accepts,
+// with the address and opcode of each line in a comment
+pub fn listing(rom: &[u8], origin: u16) -> String {
+    let mut s = String::new();
+    for (k, chunk) in rom.chunks(2).enumerate() {
+        let addr = origin as usize + k * 2;
+        let (text, raw) = match chunk {
+            [hi, lo] => {
+                let opcode = u16::from_be_bytes([*hi, *lo]);
+                (disassemble(opcode), format!("{:04x}", opcode))
+            }
+            [b] => (format!("DB 0x{:02x}", b), format!("{:02x}", b)),
+            _ => unreachable!(),
+        };
+        writeln!(s, "    {:<20}; 0x{:03x}: {}", text, addr, raw).unwrap();
+    }
+    s
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mnemonics() {
+        assert_eq!("CLS", disassemble(0x00e0));
+        assert_eq!("JP 0x2a4", disassemble(0x12a4));
+        assert_eq!("SE V3, 0x1f", disassemble(0x331f));
+        assert_eq!("SUBN VA, VB", disassemble(0x8ab7));
+        assert_eq!("DRW V0, V1, 5", disassemble(0xd015));
+        assert_eq!("LD VF, [I]", disassemble(0xff65));
+        assert_eq!("DW 0x5121", disassemble(0x5121));
+        assert_eq!("DW 0xe1ff", disassemble(0xe1ff));
+    }
+
+    #[test]
+    fn odd_length() {
+        let s = listing(&[0x00, 0xe0, 0x12], 0x200);
+        assert_eq!(
+            "    CLS                 ; 0x200: 00e0\n    DB 0x12             ; 0x202: 12\n",
+            s
+        );
+    }
+}
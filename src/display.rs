@@ -0,0 +1,128 @@
+use std::collections::VecDeque;
+
+use crate::chip8::{GFX_SIZE, GFX_SIZE_COL};
+use crate::framebuffer::Framebuffer;
+
+pub const PERSISTENCE_MODES: [&str; 3] = ["off", "antiflicker", "phosphor"];
+
+// How pixels lit in earlier frames show on the screen
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Persistence {
+    Off,
+    // Lit if lit in this or the previous frame, hides sprites erased and redrawn
+    AntiFlicker,
+    // A pixel lit k frames ago shows at decay^k of full brightness,
+    // for up to `frames` frames
+    Phosphor { frames: usize, decay: f32 },
+}
+
+impl Persistence {
+    pub fn from_name(name: &str, frames: usize, decay: f32) -> Option<Persistence> {
+        match name {
+            "off" => Some(Persistence::Off),
+            "antiflicker" => Some(Persistence::AntiFlicker),
+            "phosphor" => Some(Persistence::Phosphor {
+                frames: frames.max(1),
+                decay: decay.clamp(0.0, 1.0),
+            }),
+            _ => None,
+        }
+    }
+
+    fn frames(&self) -> usize {
+        match *self {
+            Persistence::Off => 1,
+            Persistence::AntiFlicker => 2,
+            Persistence::Phosphor { frames, .. } => frames,
+        }
+    }
+}
+
+// Turns the frames the emulator produced into pixel brightness,
+// 0 for the background colour and 255 for the foreground colour
+pub struct Display {
+    persistence: Persistence,
+    // Newest frame first
+    history: VecDeque<Framebuffer>,
+}
+
+impl Display {
+    pub fn new(persistence: Persistence) -> Display {
+        Display {
+            persistence,
+            history: VecDeque::new(),
+        }
+    }
+
+    pub fn persistence(&self) -> Persistence {
+        self.persistence
+    }
+
+    // Called once per emulated frame
+    pub fn push(&mut self, fb: &Framebuffer) {
+        self.history.push_front(*fb);
+        self.history.truncate(self.persistence.frames());
+    }
+
+    pub fn levels(&self) -> [u8; GFX_SIZE] {
+        let mut levels = [0; GFX_SIZE];
+        for (age, fb) in self.history.iter().enumerate() {
+            let level = match self.persistence {
+                Persistence::Off | Persistence::AntiFlicker => 255,
+                Persistence::Phosphor { decay, .. } => (255.0 * decay.powi(age as i32)) as u8,
+            };
+            for (y, &row) in fb.rows().iter().enumerate() {
+                if row == 0 {
+                    continue;
+                }
+                for x in 0..GFX_SIZE_COL {
+                    if row & (1 << (GFX_SIZE_COL - 1 - x)) != 0 {
+                        let l = &mut levels[y * GFX_SIZE_COL + x];
+                        *l = (*l).max(level);
+                    }
+                }
+            }
+        }
+        levels
+    }
+
+    // True while older frames still show, so the screen changes without new draws
+    pub fn fading(&self) -> bool {
+        self.history.iter().skip(1).any(|fb| *fb != self.history[0])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(x: usize) -> Framebuffer {
+        let mut fb = Framebuffer::new();
+        fb.set_pixel(x, 0, true);
+        fb
+    }
+
+    #[test]
+    fn persistence() {
+        let mut off = Display::new(Persistence::Off);
+        let mut antiflicker = Display::new(Persistence::AntiFlicker);
+        let mut phosphor = Display::new(Persistence::from_name("phosphor", 3, 0.5).unwrap());
+        for x in 0..4 {
+            off.push(&frame(x));
+            antiflicker.push(&frame(x));
+            phosphor.push(&frame(x));
+        }
+
+        assert_eq!([0, 0, 0, 255], off.levels()[..4]);
+        assert_eq!([0, 0, 255, 255], antiflicker.levels()[..4]);
+        assert_eq!([0, 63, 127, 255], phosphor.levels()[..4]);
+        assert!(phosphor.fading());
+
+        for _ in 0..3 {
+            phosphor.push(&frame(3));
+        }
+        assert_eq!([0, 0, 0, 255], phosphor.levels()[..4]);
+        assert!(!phosphor.fading());
+        assert_eq!(None, Persistence::from_name("blur", 3, 0.5));
+    }
+}
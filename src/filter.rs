@@ -0,0 +1,237 @@
+use crate::chip8::{GFX_SIZE, GFX_SIZE_COL, GFX_SIZE_ROW};
+use crate::palette::{Palette, Rgb};
+
+// Post-processing on the scaled screen, all done on the CPU
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Filter {
+    // Darkens the bottom line of every CHIP-8 pixel row
+    Scanlines,
+    // Darkens the right and bottom edge of every CHIP-8 pixel
+    Grid,
+    // Rounds the corners of every CHIP-8 pixel
+    Rounded,
+    // Adds a blurred copy of the screen to make lit pixels glow
+    Bloom,
+    // Darkens the screen towards the corners
+    Vignette,
+}
+
+pub const FILTERS: [(&str, Filter); 5] = [
+    ("scanlines", Filter::Scanlines),
+    ("grid", Filter::Grid),
+    ("rounded", Filter::Rounded),
+    ("bloom", Filter::Bloom),
+    ("vignette", Filter::Vignette),
+];
+
+impl Filter {
+    pub fn from_name(name: &str) -> Option<Filter> {
+        FILTERS.iter().find(|(n, _)| *n == name).map(|(_, f)| *f)
+    }
+}
+
+// RGBA pixels, 4 bytes each, row by row
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<u8>,
+}
+
+impl Image {
+    pub fn new(width: usize, height: usize, rgb: Rgb) -> Image {
+        Image {
+            width,
+            height,
+            pixels: [rgb[0], rgb[1], rgb[2], 0xff].repeat(width * height),
+        }
+    }
+
+    // The screen with every CHIP-8 pixel as a `scale` x `scale` square,
+    // coloured by its level from `Display::levels`
+    pub fn render(levels: &[u8; GFX_SIZE], palette: &Palette, scale: usize) -> Image {
+        let width = GFX_SIZE_COL * scale;
+        let height = GFX_SIZE_ROW * scale;
+        let mut pixels = Vec::with_capacity(width * height * 4);
+        for y in 0..height {
+            for x in 0..width {
+                let level = levels[y / scale * GFX_SIZE_COL + x / scale];
+                // Unlit pixels have a dot in the top left corner
+                let rgb = if level == 0 && x % scale == 0 && y % scale == 0 {
+                    palette.foreground
+                } else {
+                    palette.blend(level)
+                };
+                pixels.extend_from_slice(&[rgb[0], rgb[1], rgb[2], 0xff]);
+            }
+        }
+        Image {
+            width,
+            height,
+            pixels,
+        }
+    }
+
+    pub fn pixel(&self, x: usize, y: usize) -> Rgb {
+        let k = (y * self.width + x) * 4;
+        [self.pixels[k], self.pixels[k + 1], self.pixels[k + 2]]
+    }
+
+    pub(crate) fn set_pixel(&mut self, x: usize, y: usize, rgb: Rgb) {
+        let k = (y * self.width + x) * 4;
+        self.pixels[k..k + 3].copy_from_slice(&rgb);
+    }
+
+    // Multiplies the colour of a pixel by `f`
+    pub(crate) fn dim(&mut self, x: usize, y: usize, f: f32) {
+        let k = (y * self.width + x) * 4;
+        for c in &mut self.pixels[k..k + 3] {
+            *c = (*c as f32 * f) as u8;
+        }
+    }
+
+    pub fn apply(&mut self, filter: Filter, palette: &Palette, scale: usize) {
+        match filter {
+            Filter::Scanlines => self.scanlines(scale),
+            Filter::Grid => self.grid(scale),
+            Filter::Rounded => self.rounded(palette, scale),
+            Filter::Bloom => self.bloom(scale),
+            Filter::Vignette => self.vignette(),
+        }
+    }
+
+    fn scanlines(&mut self, scale: usize) {
+        if scale < 2 {
+            return;
+        }
+        for y in (scale - 1..self.height).step_by(scale) {
+            for x in 0..self.width {
+                self.dim(x, y, 0.5);
+            }
+        }
+    }
+
+    fn grid(&mut self, scale: usize) {
+        if scale < 3 {
+            return;
+        }
+        for y in 0..self.height {
+            for x in 0..self.width {
+                if x % scale == scale - 1 || y % scale == scale - 1 {
+                    self.dim(x, y, 0.75);
+                }
+            }
+        }
+    }
+
+    fn rounded(&mut self, palette: &Palette, scale: usize) {
+        let r = (scale / 3) as isize;
+        if r == 0 {
+            return;
+        }
+        let last = scale as isize - 1;
+        for y in 0..self.height {
+            for x in 0..self.width {
+                // Distance from the square with rounded corners inside the pixel
+                let (cx, cy) = ((x % scale) as isize, (y % scale) as isize);
+                let dx = (r - cx).max(cx - (last - r)).max(0);
+                let dy = (r - cy).max(cy - (last - r)).max(0);
+                if dx * dx + dy * dy > r * r {
+                    self.set_pixel(x, y, palette.background);
+                }
+            }
+        }
+    }
+
+    fn bloom(&mut self, scale: usize) {
+        let glow = box_blur(&self.pixels, self.width, self.height, scale);
+        for (k, c) in self.pixels.iter_mut().enumerate() {
+            if k % 4 != 3 {
+                *c = (*c as u32 + glow[k] as u32 / 2).min(0xff) as u8;
+            }
+        }
+    }
+
+    fn vignette(&mut self) {
+        let (cx, cy) = (self.width as f32 / 2.0, self.height as f32 / 2.0);
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let dx = (x as f32 + 0.5 - cx) / cx;
+                let dy = (y as f32 + 0.5 - cy) / cy;
+                let d2 = (dx * dx + dy * dy) / 2.0;
+                self.dim(x, y, 1.0 - 0.6 * d2);
+            }
+        }
+    }
+}
+
+// Averages every channel over a square of side 2 * radius + 1,
+// as a horizontal and then a vertical pass with running sums
+fn box_blur(pixels: &[u8], width: usize, height: usize, radius: usize) -> Vec<u8> {
+    let pass = |src: &[u8], len: usize, lines: usize, index: &dyn Fn(usize, usize) -> usize| {
+        let mut dst = vec![0; src.len()];
+        let n = (2 * radius + 1) as u32;
+        for line in 0..lines {
+            for c in 0..4 {
+                let at = |i: isize| {
+                    let i = i.clamp(0, len as isize - 1) as usize;
+                    src[index(line, i) + c] as u32
+                };
+                let mut sum: u32 = (-(radius as isize)..=radius as isize).map(at).sum();
+                for i in 0..len {
+                    dst[index(line, i) + c] = (sum / n) as u8;
+                    sum += at(i as isize + radius as isize + 1);
+                    sum -= at(i as isize - radius as isize);
+                }
+            }
+        }
+        dst
+    };
+    let h = pass(pixels, width, height, &|y, x| (y * width + x) * 4);
+    pass(&h, height, width, &|x, y| (y * width + x) * 4)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn screen() -> (Image, Palette) {
+        let palette = Palette::parse("#000000,#ffffff").unwrap();
+        let mut levels = [0; GFX_SIZE];
+        levels[GFX_SIZE_COL + 1] = 255;
+        (Image::render(&levels, &palette, 6), palette)
+    }
+
+    #[test]
+    fn render() {
+        let (image, palette) = screen();
+        assert_eq!((384, 192), (image.width, image.height));
+        assert_eq!(palette.foreground, image.pixel(0, 0));
+        assert_eq!(palette.background, image.pixel(1, 1));
+        assert_eq!(palette.foreground, image.pixel(7, 7));
+    }
+
+    #[test]
+    fn filters() {
+        let (original, palette) = screen();
+        for (name, filter) in FILTERS {
+            let (mut image, _) = screen();
+            image.apply(filter, &palette, 6);
+            assert_eq!(Some(filter), Filter::from_name(name));
+            assert!(original.pixels != image.pixels, "{}", name);
+        }
+
+        let mut image = screen().0;
+        image.apply(Filter::Scanlines, &palette, 6);
+        assert_eq!([0x7f; 3], image.pixel(8, 11));
+        assert_eq!([0xff; 3], image.pixel(8, 10));
+
+        let mut image = screen().0;
+        image.apply(Filter::Rounded, &palette, 6);
+        assert_eq!(palette.background, image.pixel(6, 6));
+        assert_eq!(palette.foreground, image.pixel(9, 9));
+
+        let mut image = screen().0;
+        image.apply(Filter::Bloom, &palette, 6);
+        assert_ne!(palette.background, image.pixel(13, 8));
+    }
+}
@@ -0,0 +1,145 @@
+use crate::chip8::{GFX_SIZE_COL, GFX_SIZE_ROW};
+
+// The screen with one bit per pixel, one u64 per row.
+// The most significant bit is the leftmost pixel, so a sprite byte
+// shifted to the top of the row and then right by X lands at column X.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Framebuffer {
+    rows: [u64; GFX_SIZE_ROW],
+    // Rows changed since the last `take_dirty`
+    dirty: DirtyRows,
+}
+
+// A set of rows, bit y for row y
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct DirtyRows(u32);
+
+impl DirtyRows {
+    pub fn all() -> DirtyRows {
+        DirtyRows(u32::MAX)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn contains(&self, y: usize) -> bool {
+        self.0 & (1 << y) != 0
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = usize> {
+        let rows = self.0;
+        (0..GFX_SIZE_ROW).filter(move |y| rows & (1 << y) != 0)
+    }
+
+    fn insert(&mut self, y: usize) {
+        self.0 |= 1 << y;
+    }
+}
+
+// Screens are equal when they show the same pixels
+impl PartialEq for Framebuffer {
+    fn eq(&self, other: &Framebuffer) -> bool {
+        self.rows == other.rows
+    }
+}
+
+impl Eq for Framebuffer {}
+
+impl Framebuffer {
+    pub fn new() -> Framebuffer {
+        Framebuffer::default()
+    }
+
+    pub fn clear(&mut self) {
+        for (y, row) in self.rows.iter_mut().enumerate() {
+            if *row != 0 {
+                *row = 0;
+                self.dirty.insert(y);
+            }
+        }
+    }
+
+    pub fn pixel(&self, x: usize, y: usize) -> bool {
+        self.rows[y] & column(x) != 0
+    }
+
+    pub fn set_pixel(&mut self, x: usize, y: usize, on: bool) {
+        if self.pixel(x, y) != on {
+            self.rows[y] ^= column(x);
+            self.dirty.insert(y);
+        }
+    }
+
+    pub fn row(&self, y: usize) -> u64 {
+        self.rows[y]
+    }
+
+    pub fn rows(&self) -> &[u64; GFX_SIZE_ROW] {
+        &self.rows
+    }
+
+    // XORs the bits into row y and returns true if any set pixel was cleared
+    pub fn xor_row(&mut self, y: usize, bits: u64) -> bool {
+        let collision = self.rows[y] & bits != 0;
+        self.rows[y] ^= bits;
+        if bits != 0 {
+            self.dirty.insert(y);
+        }
+        collision
+    }
+
+    // Returns the rows changed since the last call, for front ends
+    // that only redraw what changed
+    pub fn take_dirty(&mut self) -> DirtyRows {
+        std::mem::take(&mut self.dirty)
+    }
+}
+
+fn column(x: usize) -> u64 {
+    1 << (GFX_SIZE_COL - 1 - x)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pixels() {
+        let mut fb = Framebuffer::new();
+        fb.set_pixel(0, 0, true);
+        fb.set_pixel(GFX_SIZE_COL - 1, GFX_SIZE_ROW - 1, true);
+        assert!(fb.pixel(0, 0));
+        assert!(!fb.pixel(1, 0));
+        assert_eq!(1 << 63, fb.row(0));
+        assert_eq!(1, fb.row(GFX_SIZE_ROW - 1));
+
+        assert!(!fb.xor_row(1, 0xf0));
+        assert!(fb.xor_row(1, 0x30));
+        assert_eq!(0xc0, fb.row(1));
+
+        fb.set_pixel(0, 0, false);
+        assert!(!fb.pixel(0, 0));
+        fb.clear();
+        assert_eq!(Framebuffer::new(), fb);
+    }
+
+    #[test]
+    fn dirty() {
+        let mut fb = Framebuffer::new();
+        fb.set_pixel(3, 5, true);
+        fb.xor_row(7, 0xff);
+        fb.xor_row(9, 0);
+        let dirty = fb.take_dirty();
+        assert_eq!(vec![5, 7], dirty.iter().collect::<Vec<_>>());
+        assert!(dirty.contains(5) && !dirty.contains(9));
+        assert!(fb.take_dirty().is_empty());
+
+        // Only rows that had pixels change when the screen is cleared
+        fb.clear();
+        assert_eq!(vec![5, 7], fb.take_dirty().iter().collect::<Vec<_>>());
+        fb.clear();
+        assert!(fb.take_dirty().is_empty());
+        assert_eq!(GFX_SIZE_ROW, DirtyRows::all().iter().count());
+    }
+}
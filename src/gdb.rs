@@ -0,0 +1,1167 @@
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::error::Error;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+use crate::breakpoint::{Access, Breakpoint, Breakpoints, Event, Watchpoint};
+use crate::chip8::{Chip8, KeyBoard, Registers, State, MEMORY_SIZE, STACK_SIZE};
+use crate::history::{History, MAX_SNAPSHOTS, SNAPSHOT_INTERVAL};
+use crate::rom;
+use crate::symbols::Symbols;
+
+// Signals reported to GDB when the program stops
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+const SIGSEGV: u8 = 11;
+
+// Instructions run between looks for an interrupt from GDB
+const POLL_INTERVAL: usize = 4096;
+
+// Calls deep enough to warn that the stack is nearly full
+const STACK_WARNING: usize = STACK_SIZE - 2;
+
+// Register number -> name and size in bytes, in the order of the 'g' packet
+const REGISTERS: [(&str, usize); 21] = [
+    ("v0", 1),
+    ("v1", 1),
+    ("v2", 1),
+    ("v3", 1),
+    ("v4", 1),
+    ("v5", 1),
+    ("v6", 1),
+    ("v7", 1),
+    ("v8", 1),
+    ("v9", 1),
+    ("va", 1),
+    ("vb", 1),
+    ("vc", 1),
+    ("vd", 1),
+    ("ve", 1),
+    ("vf", 1),
+    ("i", 2),
+    ("pc", 2),
+    ("sp", 1),
+    ("dt", 1),
+    ("st", 1),
+];
+
+fn target_xml() -> String {
+    let mut xml = String::from(concat!(
+        "<?xml version=\"1.0\"?>\n",
+        "<!DOCTYPE target SYSTEM \"gdb-target.dtd\">\n",
+        "<target version=\"1.0\">\n",
+        "<feature name=\"org.rs-chip-8.cpu\">\n",
+    ));
+    for (n, (name, size)) in REGISTERS.iter().enumerate() {
+        let kind = match *name {
+            "pc" => " type=\"code_ptr\"",
+            "i" => " type=\"data_ptr\"",
+            _ => "",
+        };
+        xml += &format!(
+            "<reg name=\"{}\" bitsize=\"{}\" regnum=\"{}\"{}/>\n",
+            name,
+            size * 8,
+            n,
+            kind
+        );
+    }
+    xml + "</feature>\n</target>\n"
+}
+
+// Waits for one GDB connection on the listener and serves it until GDB
+// detaches or disconnects. `ipf` instructions make one 60 Hz timer tick.
+pub fn serve(
+    listener: &TcpListener,
+    chip8: &mut Chip8,
+    filename: &str,
+    ipf: usize,
+    symbols: Symbols,
+) -> Result<(), Box<dyn Error>> {
+    let (stream, _) = listener.accept()?;
+    stream.set_nodelay(true)?;
+    let mut session = Session {
+        stream,
+        pending: VecDeque::new(),
+        chip8,
+        filename: filename.to_string(),
+        kb: KeyBoard::new(),
+        breakpoints: BTreeSet::new(),
+        points: Breakpoints::default(),
+        ipf: ipf.max(1),
+        history: History::new(SNAPSHOT_INTERVAL, MAX_SNAPSHOTS),
+        symbols,
+        calls: BTreeMap::new(),
+        check_returns: false,
+        stack_warned: false,
+        signal: SIGTRAP,
+    };
+    session.run()
+}
+
+struct Session<'a> {
+    stream: TcpStream,
+    // Bytes read while looking for Ctrl-C, read again before the stream
+    pending: VecDeque<u8>,
+    chip8: &'a mut Chip8,
+    // Read again by `monitor reload`
+    filename: String,
+    kb: KeyBoard,
+    breakpoints: BTreeSet<u16>,
+    // Watchpoints, and the breakpoints and tracepoints of monitor commands
+    points: Breakpoints,
+    ipf: usize,
+    // For running backwards
+    history: History,
+    symbols: Symbols,
+    // Subroutines called so far, with the cycle of the first call
+    calls: BTreeMap<u16, u64>,
+    // Stop at a 00EE that does not seem to end the last subroutine called
+    check_returns: bool,
+    stack_warned: bool,
+    // Why the program last stopped
+    signal: u8,
+}
+
+enum Reply {
+    Packet(String),
+    // Detach or kill, the session ends after the reply if there is one
+    Close(Option<String>),
+}
+
+impl Session<'_> {
+    fn run(&mut self) -> Result<(), Box<dyn Error>> {
+        while let Some(packet) = self.read_packet()? {
+            match self.handle(&packet) {
+                Reply::Packet(reply) => self.send(&reply)?,
+                Reply::Close(reply) => {
+                    if let Some(reply) = reply {
+                        self.send(&reply)?;
+                    }
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn handle(&mut self, packet: &str) -> Reply {
+        let reply = match packet.as_bytes().first() {
+            Some(b'?') => format!("S{:02x}", self.signal),
+            Some(b'g') => self.read_registers(),
+            Some(b'G') => self.edited(|s| s.write_registers(&packet[1..])),
+            Some(b'p') => self.read_register(&packet[1..]).unwrap_or_else(error),
+            Some(b'P') => self.edited(|s| s.write_register(&packet[1..])),
+            Some(b'm') => self.read_memory(&packet[1..]).unwrap_or_else(error),
+            Some(b'M') => self.edited(|s| s.write_memory(&packet[1..])),
+            Some(b'Z') | Some(b'z') => {
+                let insert = packet.starts_with('Z');
+                match packet[1..].split_once(',') {
+                    Some(("0", args)) => ok(self.breakpoint(args, insert)),
+                    Some(("2", args)) => ok(self.watchpoint(args, Access::Write, insert)),
+                    Some(("3", args)) => ok(self.watchpoint(args, Access::Read, insert)),
+                    Some(("4", args)) => ok(self.watchpoint(args, Access::ReadWrite, insert)),
+                    // No hardware breakpoints
+                    _ => String::new(),
+                }
+            }
+            Some(b'b') if packet == "bs" => self.step_back(),
+            Some(b'b') if packet == "bc" => self.reverse(),
+            Some(b's') => match self.jump(&packet[1..]) {
+                Ok(()) => self.step(),
+                Err(e) => error(e),
+            },
+            Some(b'c') => match self.jump(&packet[1..]) {
+                Ok(()) => self.resume(),
+                Err(e) => error(e),
+            },
+            Some(b'H') | Some(b'T') => "OK".to_string(),
+            Some(b'D') => return Reply::Close(Some("OK".to_string())),
+            Some(b'k') => return Reply::Close(None),
+            Some(b'q') => self.query(&packet[1..]),
+            _ => String::new(),
+        };
+        Reply::Packet(reply)
+    }
+
+    fn query(&mut self, query: &str) -> String {
+        if query.starts_with("Supported") {
+            return "PacketSize=4000;qXfer:features:read+;ReverseStep+;ReverseContinue+"
+                .to_string();
+        }
+        if let Some(hex) = query.strip_prefix("Rcmd,") {
+            return match decode_hex(hex) {
+                Ok(command) => {
+                    encode_hex(self.monitor(&String::from_utf8_lossy(&command)).as_bytes())
+                }
+                Err(e) => error(e),
+            };
+        }
+        if let Some(args) = query.strip_prefix("Xfer:features:read:target.xml:") {
+            let xml = target_xml();
+            let range = parse_pair(args, ',')
+                .and_then(|(offset, len)| Some((offset, offset.checked_add(len)?)));
+            return match range {
+                Some((offset, end)) if offset <= xml.len() => {
+                    let end = end.min(xml.len());
+                    let more = if end < xml.len() { 'm' } else { 'l' };
+                    format!("{}{}", more, &xml[offset..end])
+                }
+                _ => "E01".to_string(),
+            };
+        }
+        match query {
+            "Attached" => "1".to_string(),
+            "C" => "QC1".to_string(),
+            "fThreadInfo" => "m1".to_string(),
+            "sThreadInfo" => "l".to_string(),
+            _ => String::new(),
+        }
+    }
+
+    // Commands typed after "monitor" in GDB
+    fn monitor(&mut self, command: &str) -> String {
+        let (name, args) = command
+            .trim()
+            .split_once(' ')
+            .unwrap_or((command.trim(), ""));
+        let result = match name {
+            "break" => Breakpoint::parse(args).map(|b| self.points.add(b)),
+            // trace ADDRESS [hits N] [if CONDITION] "MESSAGE"
+            "trace" => match args.split_once('"') {
+                Some((point, message)) => Breakpoint::parse(point.trim())
+                    .and_then(|b| b.with_message(message.strip_suffix('"').unwrap_or(message)))
+                    .map(|b| self.points.add(b)),
+                None => Err("expected ADDRESS [if CONDITION] \"MESSAGE\"".to_string()),
+            },
+            "delete" => match parse_address(args.trim()) {
+                Some(addr) if self.points.remove(addr) => Ok(()),
+                _ => Err(format!("no breakpoint at {}", args.trim())),
+            },
+            "breakpoints" => {
+                return self.points.iter().map(|b| format!("{}\n", b)).collect();
+            }
+            "stack" => return self.call_stack(),
+            "reset" => self.restart(false),
+            "reload" => self.restart(true),
+            "check-returns" => match args.trim() {
+                "on" | "off" => {
+                    self.check_returns = args.trim() == "on";
+                    Ok(())
+                }
+                _ => Err("expected on or off".to_string()),
+            },
+            _ => return self.query_history(command),
+        };
+        match result {
+            Ok(()) => String::new(),
+            Err(e) => format!("{}\n", e),
+        }
+    }
+
+    // Starts the program again, soft with the memory as it is or hard with
+    // the ROM read again from disk
+    fn restart(&mut self, reload: bool) -> Result<(), String> {
+        let mut fresh = self.chip8.clone();
+        fresh.reset();
+        if reload {
+            if self.filename == "-" {
+                return Err("can't reload a ROM read from stdin".to_string());
+            }
+            let rom = rom::read(&self.filename).map_err(|e| format!("{}: {}", self.filename, e))?;
+            fresh.clear_memory();
+            fresh.load_rom(&rom).map_err(|e| e.to_string())?;
+        }
+        *self.chip8 = fresh;
+        self.calls.clear();
+        self.stack_warned = false;
+        self.history.edited(self.chip8);
+        Ok(())
+    }
+
+    fn query_history(&self, command: &str) -> String {
+        let words: Vec<&str> = command.split_whitespace().collect();
+        match words[..] {
+            ["who-wrote", addr] => {
+                let Some(addr) = parse_address(addr) else {
+                    return format!("invalid address: {}\n", addr);
+                };
+                match self.history.last_write(addr) {
+                    Some(w) => format!(
+                        "0x{:03x} was last written by the instruction at 0x{:03x}, cycle {}\n",
+                        addr, w.pc, w.cycle
+                    ),
+                    None => format!(
+                        "0x{:03x} was not written since cycle {}\n",
+                        addr,
+                        self.history.first()
+                    ),
+                }
+            }
+            _ => concat!(
+                "commands:\n",
+                "  break ADDRESS [hits N] [if CONDITION]\n",
+                "  trace ADDRESS [hits N] [if CONDITION] \"MESSAGE\"\n",
+                "  delete ADDRESS\n",
+                "  breakpoints\n",
+                "  stack\n",
+                "  check-returns on|off\n",
+                "  reset\n",
+                "  reload\n",
+                "  who-wrote ADDRESS\n",
+            )
+            .to_string(),
+        }
+    }
+
+    // An address with its label, e.g. 0x20a draw+0x2
+    fn describe(&self, addr: u16) -> String {
+        match self.symbols.name(addr) {
+            Some(name) => format!("0x{:03x} {}", addr, name),
+            None => format!("0x{:03x}", addr),
+        }
+    }
+
+    // The PC and then the calls that led to it, the last call first
+    fn call_stack(&self) -> String {
+        let r = self.chip8.registers();
+        let mut text = format!("#0  {}\n", self.describe(r.pc));
+        for (n, &addr) in self.chip8.stack().iter().rev().enumerate() {
+            text += &format!("#{:<2} {}\n", n + 1, self.describe(addr));
+        }
+        if r.sp as usize >= STACK_WARNING {
+            text += &format!("stack depth {} of {}\n", r.sp, STACK_SIZE);
+        }
+        text
+    }
+
+    // Why a 00EE at the PC would not return from the last subroutine called.
+    // It should come after the subroutine's address and before any other
+    // subroutine called since.
+    fn unbalanced_return(&self) -> Option<String> {
+        let pc = self.chip8.registers().pc;
+        let &call = self.chip8.stack().last()?;
+        let opcode = self.chip8.opcode_at(call);
+        if opcode & 0xF000 != 0x2000 {
+            return None;
+        }
+        let target = opcode & 0x0FFF;
+        let other = pc > target && self.calls.range(target + 1..=pc).next().is_some();
+        if pc >= target && !other {
+            return None;
+        }
+        Some(format!(
+            "unbalanced 00EE at {}, the last call was to {} from {}",
+            self.describe(pc),
+            self.describe(target),
+            self.describe(call)
+        ))
+    }
+
+    // Applies a change from GDB, which the history can't replay
+    fn edited(&mut self, edit: impl FnOnce(&mut Self) -> Result<(), String>) -> String {
+        let result = edit(self);
+        if result.is_ok() {
+            self.history.edited(self.chip8);
+        }
+        ok(result)
+    }
+
+    fn read_registers(&self) -> String {
+        (0..REGISTERS.len()).map(|n| self.register(n)).collect()
+    }
+
+    fn write_registers(&mut self, hex: &str) -> Result<(), String> {
+        let bytes = decode_hex(hex)?;
+        let mut offset = 0;
+        for (n, (_, size)) in REGISTERS.iter().enumerate() {
+            let value = bytes
+                .get(offset..offset + size)
+                .ok_or("too few registers")?;
+            self.set_register(n, value)?;
+            offset += size;
+        }
+        Ok(())
+    }
+
+    fn read_register(&self, args: &str) -> Result<String, String> {
+        let n = parse_hex(args)?;
+        if n >= REGISTERS.len() {
+            return Err(format!("no register {}", n));
+        }
+        Ok(self.register(n))
+    }
+
+    fn write_register(&mut self, args: &str) -> Result<(), String> {
+        let (n, value) = args.split_once('=').ok_or("expected n=value")?;
+        self.set_register(parse_hex(n)?, &decode_hex(value)?)
+    }
+
+    // Little endian hex of register n
+    fn register(&self, n: usize) -> String {
+        let r = self.chip8.registers();
+        let value = match n {
+            0..=15 => r.v[n] as u16,
+            16 => r.i,
+            17 => r.pc,
+            18 => r.sp,
+            19 => r.delay_timer as u16,
+            _ => r.sound_timer as u16,
+        };
+        encode_hex(&value.to_le_bytes()[..REGISTERS[n].1])
+    }
+
+    fn set_register(&mut self, n: usize, bytes: &[u8]) -> Result<(), String> {
+        let size = REGISTERS.get(n).ok_or(format!("no register {}", n))?.1;
+        if bytes.len() != size {
+            return Err(format!("register {} has {} bytes", n, size));
+        }
+        let value = bytes.iter().rev().fold(0, |v, &b| v << 8 | b as u16);
+        let mut r: Registers = self.chip8.registers();
+        match n {
+            0..=15 => r.v[n] = value as u8,
+            16 => r.i = value,
+            17 => r.pc = value,
+            18 if value as usize > STACK_SIZE => {
+                return Err(format!("stack pointer out of range: {}", value))
+            }
+            18 => r.sp = value,
+            19 => r.delay_timer = value as u8,
+            _ => r.sound_timer = value as u8,
+        }
+        self.chip8.set_registers(r);
+        Ok(())
+    }
+
+    fn read_memory(&self, args: &str) -> Result<String, String> {
+        let (addr, len) = parse_pair(args, ',').ok_or("expected addr,length")?;
+        if addr >= MEMORY_SIZE {
+            return Err(format!("read outside of memory: 0x{:x}", addr));
+        }
+        let end = addr.checked_add(len).ok_or("length out of range")?;
+        let end = end.min(MEMORY_SIZE);
+        Ok(encode_hex(&self.chip8.memory()[addr..end]))
+    }
+
+    fn write_memory(&mut self, args: &str) -> Result<(), String> {
+        let (range, hex) = args.split_once(':').ok_or("expected addr,length:data")?;
+        let (addr, len) = parse_pair(range, ',').ok_or("expected addr,length")?;
+        let bytes = decode_hex(hex)?;
+        if bytes.len() != len {
+            return Err("length does not match the data".to_string());
+        }
+        self.chip8.write_memory(addr, &bytes)
+    }
+
+    // The addr,kind of Z0 and z0
+    fn breakpoint(&mut self, args: &str, insert: bool) -> Result<(), String> {
+        let addr = args.split(',').next().unwrap_or_default();
+        let addr = u16::try_from(parse_hex(addr)?).map_err(|e| e.to_string())?;
+        if insert {
+            self.breakpoints.insert(addr);
+        } else {
+            self.breakpoints.remove(&addr);
+        }
+        Ok(())
+    }
+
+    // The addr,length of Z2 to Z4 and z2 to z4
+    fn watchpoint(&mut self, args: &str, access: Access, insert: bool) -> Result<(), String> {
+        let (addr, len) = parse_pair(args, ',').ok_or("expected addr,length")?;
+        if addr >= MEMORY_SIZE {
+            return Err(format!("watch outside of memory: 0x{:x}", addr));
+        }
+        let watchpoint = Watchpoint {
+            addr: u16::try_from(addr).map_err(|e| e.to_string())?,
+            len: u16::try_from(len).map_err(|e| e.to_string())?,
+            access,
+        };
+        if insert {
+            self.points.watch(watchpoint);
+        } else {
+            self.points.unwatch(&watchpoint);
+        }
+        Ok(())
+    }
+
+    // s and c can resume at another address
+    fn jump(&mut self, addr: &str) -> Result<(), String> {
+        if !addr.is_empty() {
+            let mut r = self.chip8.registers();
+            r.pc = u16::try_from(parse_hex(addr)?).map_err(|e| e.to_string())?;
+            self.chip8.set_registers(r);
+            self.history.edited(self.chip8);
+        }
+        Ok(())
+    }
+
+    // Runs one instruction, Some(stop reply) if the program can't go on
+    fn execute(&mut self) -> Option<String> {
+        let r = self.chip8.registers();
+        let opcode = self.chip8.opcode_at(r.pc);
+        if opcode == 0x00EE && self.check_returns {
+            if let Some(message) = self.unbalanced_return() {
+                return Some(self.stop(SIGTRAP, &message));
+            }
+        }
+        if opcode & 0xF000 == 0x2000 {
+            let cycle = self.chip8.cycles();
+            self.calls.entry(opcode & 0x0FFF).or_insert(cycle);
+        }
+        let watched = self.points.accessed(self.chip8);
+        let (kb, ipf) = (&self.kb, self.ipf);
+        let result = self.history.step(self.chip8, |chip8| tick(chip8, kb, ipf));
+        let depth = self.chip8.registers().sp as usize;
+        if depth >= STACK_WARNING && !self.stack_warned {
+            let message = format!(
+                "stack depth {} of {} at {}",
+                depth,
+                STACK_SIZE,
+                self.describe(r.pc)
+            );
+            self.log(&message);
+        }
+        self.stack_warned = depth >= STACK_WARNING;
+        match result {
+            Ok(State::Running) => watched.map(|(access, addr)| {
+                let kind = match access {
+                    Access::Read => "rwatch",
+                    Access::Write => "watch",
+                    Access::ReadWrite => "awatch",
+                };
+                self.signal = SIGTRAP;
+                format!("T{:02x}{}:{:x};", SIGTRAP, kind, addr)
+            }),
+            Ok(State::Halted) => Some(self.stop(SIGTRAP, "program finished")),
+            Ok(State::Trapped(addr)) => {
+                let message = format!("memory trap at 0x{:x}", addr);
+                Some(self.stop(SIGSEGV, &message))
+            }
+            Err(e) => {
+                let message = format!("{} at {}", e, self.describe(r.pc));
+                Some(self.stop(SIGILL, &message))
+            }
+        }
+    }
+
+    fn step(&mut self) -> String {
+        self.execute().unwrap_or_else(|| self.stop(SIGTRAP, ""))
+    }
+
+    // Runs until a breakpoint, the program stops or GDB interrupts
+    fn resume(&mut self) -> String {
+        let mut n: usize = 0;
+        loop {
+            if let Some(reply) = self.execute() {
+                return reply;
+            }
+            // Tracepoints log even where the program stops
+            let mut stop = self.breakpoints.contains(&self.chip8.registers().pc);
+            for event in self.points.hit(self.chip8) {
+                match event {
+                    Event::Stop => stop = true,
+                    Event::Log(message) => self.log(&message),
+                }
+            }
+            if stop {
+                return self.stop(SIGTRAP, "");
+            }
+            n += 1;
+            if n.is_multiple_of(POLL_INTERVAL) && self.interrupted() {
+                return self.stop(SIGINT, "");
+            }
+        }
+    }
+
+    fn step_back(&mut self) -> String {
+        let (kb, ipf) = (&self.kb, self.ipf);
+        let step = |chip8: &mut Chip8| {
+            let _ = tick(chip8, kb, ipf);
+        };
+        let found = self.history.step_back(self.chip8, step);
+        self.forget_calls();
+        if found {
+            self.stop(SIGTRAP, "")
+        } else {
+            self.history_start()
+        }
+    }
+
+    // Runs backwards to the previous breakpoint
+    fn reverse(&mut self) -> String {
+        let (kb, ipf) = (&self.kb, self.ipf);
+        let step = |chip8: &mut Chip8| {
+            let _ = tick(chip8, kb, ipf);
+        };
+        let (breakpoints, points) = (&self.breakpoints, &self.points);
+        let at_breakpoint = |chip8: &Chip8| {
+            breakpoints.contains(&chip8.registers().pc)
+                || points.matches(chip8)
+                || points.accessed(chip8).is_some()
+        };
+        let found = self.history.reverse(self.chip8, step, at_breakpoint);
+        self.forget_calls();
+        if found {
+            self.stop(SIGTRAP, "")
+        } else {
+            self.history_start()
+        }
+    }
+
+    // Drops the calls made after the point the machine went back to
+    fn forget_calls(&mut self) {
+        let cycle = self.chip8.cycles();
+        self.calls.retain(|_, &mut first| first < cycle);
+    }
+
+    // Tells GDB there is no more history to go back through
+    fn history_start(&mut self) -> String {
+        self.signal = SIGTRAP;
+        format!("T{:02x}replaylog:begin;", SIGTRAP)
+    }
+
+    // Sends the message to the GDB console and returns the stop reply
+    fn stop(&mut self, signal: u8, message: &str) -> String {
+        if !message.is_empty() {
+            self.log(message);
+        }
+        self.signal = signal;
+        format!("S{:02x}", signal)
+    }
+
+    // Prints a line on the GDB console
+    fn log(&mut self, message: &str) {
+        let output = encode_hex(format!("{}\n", message).as_bytes());
+        // The next reply fails the same way if the connection is gone
+        let _ = self.send(&format!("O{}", output));
+    }
+
+    // True if GDB sent Ctrl-C
+    fn interrupted(&mut self) -> bool {
+        if self.stream.set_nonblocking(true).is_err() {
+            return false;
+        }
+        let mut byte = [0];
+        let read = self.stream.read(&mut byte);
+        let _ = self.stream.set_nonblocking(false);
+        match read {
+            Ok(1) if byte[0] == 0x03 => true,
+            Ok(1) => {
+                self.pending.push_back(byte[0]);
+                false
+            }
+            _ => false,
+        }
+    }
+
+    // The next packet, None when GDB disconnects
+    fn read_packet(&mut self) -> io::Result<Option<String>> {
+        loop {
+            // Skip acks and interrupts while stopped
+            match self.read_byte()? {
+                Some(b'$') => (),
+                Some(_) => continue,
+                None => return Ok(None),
+            }
+            let mut data = Vec::new();
+            loop {
+                match self.read_byte()? {
+                    Some(b'#') => break,
+                    Some(b) => data.push(b),
+                    None => return Ok(None),
+                }
+            }
+            let sum = match (self.read_byte()?, self.read_byte()?) {
+                (Some(a), Some(b)) => [a, b],
+                _ => return Ok(None),
+            };
+            let expected = std::str::from_utf8(&sum)
+                .ok()
+                .and_then(|s| u8::from_str_radix(s, 16).ok());
+            if expected == Some(checksum(&data)) {
+                self.stream.write_all(b"+")?;
+                return Ok(Some(String::from_utf8_lossy(&data).into_owned()));
+            }
+            self.stream.write_all(b"-")?;
+        }
+    }
+
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        if let Some(byte) = self.pending.pop_front() {
+            return Ok(Some(byte));
+        }
+        let mut byte = [0];
+        loop {
+            return match self.stream.read(&mut byte) {
+                Ok(0) => Ok(None),
+                Ok(_) => Ok(Some(byte[0])),
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => Err(e),
+            };
+        }
+    }
+
+    // Sends a packet and waits for GDB to acknowledge it
+    fn send(&mut self, data: &str) -> io::Result<()> {
+        let packet = format!("${}#{:02x}", data, checksum(data.as_bytes()));
+        loop {
+            self.stream.write_all(packet.as_bytes())?;
+            match self.read_byte()? {
+                Some(b'-') => continue,
+                // A packet that came in before the ack
+                Some(b'$') => {
+                    self.pending.push_front(b'$');
+                    return Ok(());
+                }
+                _ => return Ok(()),
+            }
+        }
+    }
+}
+
+// One instruction, with a 60 Hz timer tick every `ipf` instructions.
+// Going back runs this again, so it only depends on the machine.
+fn tick(chip8: &mut Chip8, kb: &KeyBoard, ipf: usize) -> Result<State, String> {
+    let cycle = chip8.cycles();
+    let result = chip8.step(kb);
+    if chip8.cycles() > cycle && chip8.cycles().is_multiple_of(ipf as u64) {
+        chip8.update_timers();
+    }
+    result
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0, |sum, &b| sum.wrapping_add(b))
+}
+
+fn ok(result: Result<(), String>) -> String {
+    match result {
+        Ok(()) => "OK".to_string(),
+        Err(e) => error(e),
+    }
+}
+
+// GDB only shows the error number
+fn error(_: String) -> String {
+    "E01".to_string()
+}
+
+fn parse_hex(s: &str) -> Result<usize, String> {
+    usize::from_str_radix(s, 16).map_err(|_| format!("invalid number: {}", s))
+}
+
+// An address as typed in GDB, hex with or without 0x
+fn parse_address(s: &str) -> Option<u16> {
+    let hex = s.strip_prefix("0x").unwrap_or(s);
+    u16::from_str_radix(hex, 16).ok()
+}
+
+fn parse_pair(s: &str, separator: char) -> Option<(usize, usize)> {
+    let (a, b) = s.split_once(separator)?;
+    Some((parse_hex(a).ok()?, parse_hex(b).ok()?))
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn decode_hex(hex: &str) -> Result<Vec<u8>, String> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return Err(format!("invalid hex: {}", hex));
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|k| u8::from_str_radix(&hex[k..k + 2], 16).map_err(|e| e.to_string()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    // A client that speaks the protocol like GDB does
+    struct Client {
+        stream: TcpStream,
+    }
+
+    impl Client {
+        // One byte at a time, so nothing after the packet is read
+        fn read_byte(&mut self) -> u8 {
+            let mut byte = [0];
+            self.stream.read_exact(&mut byte).unwrap();
+            byte[0]
+        }
+
+        fn read_packet(&mut self) -> String {
+            let mut b = self.read_byte();
+            while b == b'+' {
+                b = self.read_byte();
+            }
+            assert_eq!(b'$', b);
+            let mut data = Vec::new();
+            loop {
+                match self.read_byte() {
+                    b'#' => break,
+                    b => data.push(b),
+                }
+            }
+            let sum = [self.read_byte(), self.read_byte()];
+            assert_eq!(format!("{:02x}", checksum(&data)).as_bytes(), sum);
+            self.stream.write_all(b"+").unwrap();
+            String::from_utf8(data).unwrap()
+        }
+
+        fn send(&mut self, data: &str) {
+            let packet = format!("${}#{:02x}", data, checksum(data.as_bytes()));
+            self.stream.write_all(packet.as_bytes()).unwrap();
+        }
+
+        fn request(&mut self, data: &str) -> String {
+            self.send(data);
+            self.read_packet()
+        }
+    }
+
+    fn session(rom: &[u8]) -> (Client, thread::JoinHandle<Chip8>) {
+        session_with_symbols(rom, Symbols::default())
+    }
+
+    fn session_with_symbols(rom: &[u8], symbols: Symbols) -> (Client, thread::JoinHandle<Chip8>) {
+        connect(rom, "-", symbols)
+    }
+
+    fn connect(
+        rom: &[u8],
+        filename: &str,
+        symbols: Symbols,
+    ) -> (Client, thread::JoinHandle<Chip8>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let rom = rom.to_vec();
+        let filename = filename.to_string();
+        let server = thread::spawn(move || {
+            let mut chip8 = Chip8::new();
+            chip8.load_rom(&rom).unwrap();
+            serve(&listener, &mut chip8, &filename, 10, symbols).unwrap();
+            chip8
+        });
+        let stream = TcpStream::connect(addr).unwrap();
+        stream.set_nodelay(true).unwrap();
+        // Fail instead of hanging when the server does not answer
+        let timeout = std::time::Duration::from_secs(10);
+        stream.set_read_timeout(Some(timeout)).unwrap();
+        (Client { stream }, server)
+    }
+
+    #[test]
+    fn registers_and_memory() {
+        let (mut gdb, server) = session(&[0x60, 0x12, 0xa3, 0x45, 0x12, 0x04]);
+        assert!(gdb
+            .request("qSupported:xmlRegisters=i386")
+            .contains("qXfer:features:read+"));
+        let xml = gdb.request("qXfer:features:read:target.xml:0,fff");
+        assert!(xml.starts_with("l<?xml"));
+        assert!(xml.contains("<reg name=\"pc\" bitsize=\"16\" regnum=\"17\" type=\"code_ptr\"/>"));
+        assert_eq!("S05", gdb.request("?"));
+
+        assert_eq!("S05", gdb.request("s"));
+        assert_eq!("S05", gdb.request("s"));
+        let registers = gdb.request("g");
+        assert_eq!(2 * 23, registers.len());
+        assert_eq!("12", &registers[..2]);
+        // I and PC, little endian
+        assert_eq!("45030402", &registers[32..40]);
+        assert_eq!("0402", gdb.request("p11"));
+
+        assert_eq!("OK", gdb.request("P3=7f"));
+        assert_eq!("7f", gdb.request("p3"));
+        assert_eq!("E01", gdb.request("P3=7f00"));
+        assert_eq!("E01", gdb.request("p15"));
+
+        assert_eq!("6012a345", gdb.request("m200,4"));
+        assert_eq!("OK", gdb.request("M300,2:beef"));
+        assert_eq!("beef", gdb.request("m300,2"));
+        assert_eq!("E01", gdb.request("m1000,1"));
+        assert_eq!("E01", gdb.request("Mfff,2:0000"));
+        // Lengths and addresses that overflow
+        assert_eq!("E01", gdb.request("m1,ffffffffffffffff"));
+        assert_eq!("E01", gdb.request("Mffffffffffffffff,1:00"));
+        assert_eq!("OK", gdb.request("M0,0:"));
+        assert_eq!(
+            "E01",
+            gdb.request("qXfer:features:read:target.xml:1,ffffffffffffffff")
+        );
+
+        assert_eq!("OK", gdb.request("D"));
+        let chip8 = server.join().unwrap();
+        assert_eq!(0x7f, chip8.registers().v[3]);
+        assert_eq!([0xbe, 0xef], chip8.memory()[0x300..0x302]);
+    }
+
+    #[test]
+    fn breakpoints() {
+        // V0 += 1 forever, with a breakpoint on the jump
+        let (mut gdb, server) = session(&[0x70, 0x01, 0x12, 0x00]);
+        assert_eq!("OK", gdb.request("Z0,202,2"));
+        assert_eq!("", gdb.request("Z1,202,2"));
+        assert_eq!("S05", gdb.request("c"));
+        assert_eq!("0202", gdb.request("p11"));
+        assert_eq!("S05", gdb.request("c"));
+        assert_eq!("02", gdb.request("p0"));
+
+        // Ctrl-C stops a program that runs forever
+        assert_eq!("OK", gdb.request("z0,202,2"));
+        gdb.send("c");
+        thread::sleep(std::time::Duration::from_millis(50));
+        gdb.stream.write_all(&[0x03]).unwrap();
+        assert_eq!("S02", gdb.read_packet());
+        assert_eq!("S02", gdb.request("?"));
+
+        // A packet sent while running is answered once the program stops
+        gdb.send("c");
+        thread::sleep(std::time::Duration::from_millis(50));
+        gdb.stream.write_all(b"$p0#a0\x03").unwrap();
+        assert_eq!("S02", gdb.read_packet());
+        let v0 = gdb.read_packet();
+        assert_eq!(v0, gdb.request("p0"));
+
+        assert_eq!("OK", gdb.request("D"));
+        assert!(server.join().unwrap().cycles() > 4);
+    }
+
+    #[test]
+    fn reverse() {
+        // V0 += 1, store it at 0x300 and loop
+        let (mut gdb, server) = session(&[0x70, 0x01, 0xa3, 0x00, 0xf0, 0x55, 0x12, 0x00]);
+        assert!(gdb.request("qSupported").contains("ReverseContinue+"));
+        assert_eq!("T05replaylog:begin;", gdb.request("bs"));
+        assert_eq!("OK", gdb.request("Z0,204,2"));
+        for _ in 0..3 {
+            assert_eq!("S05", gdb.request("c"));
+        }
+        assert_eq!("03", gdb.request("p0"));
+
+        assert_eq!("S05", gdb.request("bs"));
+        assert_eq!("0202", gdb.request("p11"));
+        assert_eq!("S05", gdb.request("bc"));
+        assert_eq!("0402", gdb.request("p11"));
+        assert_eq!("02", gdb.request("p0"));
+        assert_eq!("01", gdb.request("m300,1"));
+
+        let who = |addr: &str| encode_hex(format!("who-wrote {}", addr).as_bytes());
+        let reply = gdb.request(&format!("qRcmd,{}", who("0x300")));
+        let reply = String::from_utf8(decode_hex(&reply).unwrap()).unwrap();
+        assert_eq!(
+            "0x300 was last written by the instruction at 0x204, cycle 2\n",
+            reply
+        );
+        let reply = gdb.request(&format!("qRcmd,{}", who("301")));
+        let reply = String::from_utf8(decode_hex(&reply).unwrap()).unwrap();
+        assert_eq!("0x301 was not written since cycle 0\n", reply);
+
+        // Back to the first stop, then the start where nothing was written yet
+        assert_eq!("S05", gdb.request("bc"));
+        assert_eq!("01", gdb.request("p0"));
+        assert_eq!("T05replaylog:begin;", gdb.request("bc"));
+        assert_eq!("0002", gdb.request("p11"));
+        assert_eq!("00", gdb.request("m300,1"));
+        gdb.send("k");
+        server.join().unwrap();
+    }
+
+    fn monitor(gdb: &mut Client, command: &str) -> String {
+        let reply = gdb.request(&format!("qRcmd,{}", encode_hex(command.as_bytes())));
+        String::from_utf8(decode_hex(&reply).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn watchpoints_and_monitor_breakpoints() {
+        // V0 += 1, store it at 0x300 + V0 and loop
+        let rom = &[0x70, 0x01, 0xa3, 0x00, 0xf0, 0x1e, 0xf0, 0x55, 0x12, 0x00];
+        let (mut gdb, server) = session(rom);
+        assert_eq!("OK", gdb.request("Z2,302,1"));
+        assert_eq!("T05watch:302;", gdb.request("c"));
+        assert_eq!("0802", gdb.request("p11"));
+        assert_eq!("OK", gdb.request("z2,302,1"));
+        assert_eq!("E01", gdb.request("Z2,2000,1"));
+
+        assert_eq!("", monitor(&mut gdb, "break 0x208 if v0 >= 4 && I > 0x300"));
+        assert_eq!("", monitor(&mut gdb, "trace 0x202 hits 2 \"v0={v0}\""));
+        gdb.send("c");
+        // The tracepoint logs from its second hit on
+        let output = gdb.read_packet();
+        assert_eq!(b"v0=4\n"[..], decode_hex(&output[1..]).unwrap());
+        assert_eq!("S05", gdb.read_packet());
+        assert_eq!("04", gdb.request("p0"));
+        assert_eq!(
+            "break 0x208 if (v0 >= 0x4 && i > 0x300), hit count 1\n\
+             trace 0x202 hits 2 \"v0={v0}\", hit count 2\n",
+            monitor(&mut gdb, "breakpoints")
+        );
+
+        // Back to the previous stop of a breakpoint, and then to the write before it
+        assert_eq!("invalid address: x\n", monitor(&mut gdb, "break x"));
+        assert_eq!("", monitor(&mut gdb, "delete 0x208"));
+        assert_eq!("", monitor(&mut gdb, "break 0x208 hits 5"));
+        assert_eq!("S05", gdb.request("bc"));
+        assert_eq!("0802", gdb.request("p11"));
+        assert_eq!("03", gdb.request("p0"));
+        assert_eq!("", monitor(&mut gdb, "delete 0x208"));
+        assert_eq!("OK", gdb.request("Z4,302,1"));
+        assert_eq!("S05", gdb.request("bc"));
+        assert_eq!("0602", gdb.request("p11"));
+        assert_eq!("02", gdb.request("p0"));
+        assert_eq!(
+            "no breakpoint at 0x300\n",
+            monitor(&mut gdb, "delete 0x300")
+        );
+        assert!(monitor(&mut gdb, "help").starts_with("commands:"));
+        gdb.send("k");
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn call_stack() {
+        let src = "
+            start:  CALL outer
+                    JP start
+            fail:   RET
+            outer:  CALL inner
+                    JP fail
+            inner:  RET
+        ";
+        let program = crate::asm::assemble(src, 0x200).unwrap();
+        let symbols = Symbols::from_labels(&program.labels);
+        let (mut gdb, server) = session_with_symbols(&program.bytes, symbols);
+        assert_eq!("S05", gdb.request("s"));
+        assert_eq!("S05", gdb.request("s"));
+        assert_eq!(
+            "#0  0x20a inner\n#1  0x206 outer\n#2  0x200 start\n",
+            monitor(&mut gdb, "stack")
+        );
+        assert_eq!("E01", gdb.request("P12=11"));
+
+        // The RET at fail returns from outer, which was jumped out of
+        assert_eq!("", monitor(&mut gdb, "check-returns on"));
+        gdb.send("c");
+        let output = gdb.read_packet();
+        assert_eq!(
+            "unbalanced 00EE at 0x204 fail, the last call was to 0x206 outer from 0x200 start\n"
+                .as_bytes(),
+            decode_hex(&output[1..]).unwrap()
+        );
+        assert_eq!("S05", gdb.read_packet());
+        assert_eq!("0402", gdb.request("p11"));
+        gdb.send("k");
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn reverse_forgets_calls() {
+        let src = "
+            main:   CALL sub
+                    CALL mid
+            end:    JP end
+            sub:    LD V0, 1
+            mid:    LD V1, 2
+                    RET
+        ";
+        let program = crate::asm::assemble(src, 0x200).unwrap();
+        let (mut gdb, server) = session(&program.bytes);
+        assert_eq!("", monitor(&mut gdb, "check-returns on"));
+        gdb.send("c");
+        assert!(gdb.read_packet().starts_with('O'));
+        assert_eq!("S05", gdb.read_packet());
+        assert_eq!("0402", gdb.request("p11"));
+
+        // mid is not called yet, so the RET of sub is not after another subroutine
+        assert_eq!("T05replaylog:begin;", gdb.request("bc"));
+        gdb.send("c");
+        assert!(gdb.read_packet().starts_with('O'));
+        assert_eq!("S05", gdb.read_packet());
+        assert_eq!("0402", gdb.request("p11"));
+        gdb.send("k");
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn stack_overflow() {
+        // Calls itself forever
+        let (mut gdb, server) = session(&[0x22, 0x00]);
+        gdb.send("c");
+        let mut output = Vec::new();
+        for _ in 0..2 {
+            let packet = gdb.read_packet();
+            output.push(String::from_utf8(decode_hex(&packet[1..]).unwrap()).unwrap());
+        }
+        assert_eq!(
+            vec![
+                "stack depth 14 of 16 at 0x200\n",
+                "stack overflow at 0x200\n"
+            ],
+            output
+        );
+        assert_eq!("S04", gdb.read_packet());
+        assert!(monitor(&mut gdb, "stack").ends_with("#16 0x200\nstack depth 16 of 16\n"));
+        gdb.send("k");
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn stops() {
+        // An unknown opcode and then a jump to itself
+        let (mut gdb, server) = session(&[0x00, 0x00, 0x12, 0x04, 0x12, 0x04]);
+        gdb.send("c");
+        let output = gdb.read_packet();
+        assert!(output.starts_with('O'));
+        let message = decode_hex(&output[1..]).unwrap();
+        assert!(String::from_utf8(message)
+            .unwrap()
+            .contains("unknown opcode"));
+        assert_eq!("S04", gdb.read_packet());
+
+        assert_eq!("OK", gdb.request("P11=0202"));
+        gdb.send("c");
+        assert!(gdb.read_packet().starts_with('O'));
+        assert_eq!("S05", gdb.read_packet());
+        gdb.send("k");
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn reset_and_reload() {
+        let path = std::env::temp_dir().join(format!("rs-chip-8-gdb-{}.ch8", std::process::id()));
+        let rom = [0x60, 0x12, 0x12, 0x02];
+        std::fs::write(&path, rom).unwrap();
+        let (mut gdb, server) = connect(&rom, path.to_str().unwrap(), Symbols::default());
+        assert_eq!("S05", gdb.request("s"));
+        assert_eq!("12", gdb.request("p0"));
+        assert_eq!("OK", gdb.request("M300,1:ff"));
+
+        // A reset keeps the memory, and there is nothing before it to go back to
+        assert_eq!("", monitor(&mut gdb, "reset"));
+        assert_eq!("00", gdb.request("p0"));
+        assert_eq!("0002", gdb.request("p11"));
+        assert_eq!("ff", gdb.request("m300,1"));
+        assert_eq!("T05replaylog:begin;", gdb.request("bs"));
+
+        // A reload loads the ROM as it is on disk now
+        std::fs::write(&path, [0x61, 0x34, 0x12, 0x02]).unwrap();
+        assert_eq!("S05", gdb.request("s"));
+        assert_eq!("", monitor(&mut gdb, "reload"));
+        assert_eq!("00", gdb.request("m300,1"));
+        assert_eq!("S05", gdb.request("s"));
+        assert_eq!("34", gdb.request("p1"));
+
+        // The program keeps running when the ROM can't be read
+        std::fs::remove_file(&path).unwrap();
+        assert!(monitor(&mut gdb, "reload").starts_with(path.to_str().unwrap()));
+        assert_eq!("34", gdb.request("p1"));
+        assert_eq!("S05", gdb.request("bs"));
+        assert_eq!("T05replaylog:begin;", gdb.request("bs"));
+        gdb.send("k");
+        server.join().unwrap();
+    }
+}
@@ -0,0 +1,272 @@
+use std::collections::VecDeque;
+
+use crate::chip8::{Chip8, MEMORY_SIZE};
+use crate::instruction::decode;
+
+// Instructions between snapshots, and how many snapshots are kept. Going
+// back restores the snapshot before the target and runs forward from it.
+pub const SNAPSHOT_INTERVAL: u64 = 1000;
+pub const MAX_SNAPSHOTS: usize = 256;
+
+// The memory written by one instruction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryWrite {
+    pub cycle: u64,
+    pub pc: u16,
+    pub addr: u16,
+    pub len: u16,
+}
+
+impl MemoryWrite {
+    // Writes past the end of memory wrap around to the start
+    pub fn contains(&self, addr: u16) -> bool {
+        let offset = (addr as usize + MEMORY_SIZE - self.addr as usize) % MEMORY_SIZE;
+        offset < self.len as usize
+    }
+}
+
+// Remembers earlier states of a machine so that it can run backwards.
+// The machine must run the same way again from a snapshot, so every step
+// has to go through `step` with the same step function.
+pub struct History {
+    snapshots: VecDeque<Chip8>,
+    writes: VecDeque<MemoryWrite>,
+    interval: u64,
+    limit: usize,
+}
+
+impl History {
+    pub fn new(interval: u64, limit: usize) -> History {
+        History {
+            snapshots: VecDeque::new(),
+            writes: VecDeque::new(),
+            interval: interval.max(1),
+            limit: limit.max(1),
+        }
+    }
+
+    // Runs one instruction with `step`, remembering what it writes
+    pub fn step<T>(&mut self, chip8: &mut Chip8, step: impl FnOnce(&mut Chip8) -> T) -> T {
+        let cycle = chip8.cycles();
+        let due = match self.snapshots.back() {
+            Some(last) => cycle >= last.cycles() + self.interval,
+            None => true,
+        };
+        if due {
+            self.snapshot(chip8);
+        }
+        let r = chip8.registers();
+        let (_, len) = decode(chip8.opcode_at(r.pc)).memory_access();
+        let result = step(chip8);
+        if len > 0 && chip8.cycles() > cycle {
+            self.writes.push_back(MemoryWrite {
+                cycle,
+                pc: r.pc,
+                addr: r.i % MEMORY_SIZE as u16,
+                len,
+            });
+        }
+        result
+    }
+
+    fn snapshot(&mut self, chip8: &Chip8) {
+        self.snapshots.push_back(chip8.clone());
+        if self.snapshots.len() > self.limit {
+            self.snapshots.pop_front();
+            let first = self.first();
+            while self.writes.front().is_some_and(|w| w.cycle < first) {
+                self.writes.pop_front();
+            }
+        }
+    }
+
+    // The machine was changed from outside, e.g. by a debugger. What it
+    // did after this point can't happen again, so it is forgotten.
+    pub fn edited(&mut self, chip8: &Chip8) {
+        self.forget(chip8.cycles());
+        self.snapshots.retain(|s| s.cycles() < chip8.cycles());
+        self.snapshot(chip8);
+    }
+
+    // The earliest cycle that can be gone back to
+    pub fn first(&self) -> u64 {
+        self.snapshots.front().map_or(0, |s| s.cycles())
+    }
+
+    // Goes back one instruction, false at the start of the history
+    pub fn step_back(&mut self, chip8: &mut Chip8, step: impl FnMut(&mut Chip8)) -> bool {
+        match chip8.cycles().checked_sub(1) {
+            Some(cycle) if cycle >= self.first() && !self.snapshots.is_empty() => {
+                self.goto(chip8, cycle, step);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // Goes back to the last state before the current one where `stop` is
+    // true. Without one it goes to the start of the history and returns false.
+    pub fn reverse(
+        &mut self,
+        chip8: &mut Chip8,
+        mut step: impl FnMut(&mut Chip8),
+        stop: impl Fn(&Chip8) -> bool,
+    ) -> bool {
+        let mut end = chip8.cycles();
+        // Search from the latest snapshot backwards, each up to the next one
+        for k in (0..self.snapshots.len()).rev() {
+            let mut m = self.snapshots[k].clone();
+            let mut found = None;
+            while m.cycles() < end {
+                if stop(&m) {
+                    found = Some(m.cycles());
+                }
+                if !advance(&mut m, &mut step) {
+                    break;
+                }
+            }
+            if let Some(cycle) = found {
+                self.goto(chip8, cycle, step);
+                return true;
+            }
+            end = self.snapshots[k].cycles();
+        }
+        let first = self.first();
+        if !self.snapshots.is_empty() {
+            self.goto(chip8, first, step);
+        }
+        false
+    }
+
+    // Restores the snapshot before `cycle` and runs up to it
+    fn goto(&mut self, chip8: &mut Chip8, cycle: u64, mut step: impl FnMut(&mut Chip8)) {
+        let Some(snapshot) = self.snapshots.iter().rev().find(|s| s.cycles() <= cycle) else {
+            return;
+        };
+        *chip8 = snapshot.clone();
+        while chip8.cycles() < cycle && advance(chip8, &mut step) {}
+        self.forget(chip8.cycles());
+    }
+
+    // Drops what happened at and after `cycle`, keeping its snapshot
+    fn forget(&mut self, cycle: u64) {
+        self.snapshots.retain(|s| s.cycles() <= cycle);
+        self.writes.retain(|w| w.cycle < cycle);
+    }
+
+    // The last write to an address, if it is still in the history
+    pub fn last_write(&self, addr: u16) -> Option<&MemoryWrite> {
+        self.writes.iter().rev().find(|w| w.contains(addr))
+    }
+}
+
+// Runs one instruction, false if the machine can't go on
+fn advance(chip8: &mut Chip8, step: &mut impl FnMut(&mut Chip8)) -> bool {
+    let cycle = chip8.cycles();
+    step(chip8);
+    chip8.cycles() > cycle
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chip8::KeyBoard;
+
+    // Counts V0 up, stores it at 0x300 + V0 and loops
+    const ROM: [u8; 12] = [
+        0x70, 0x01, 0xa3, 0x00, 0xf0, 0x1e, 0xf0, 0x55, 0x12, 0x00, 0x00, 0x00,
+    ];
+
+    fn step(chip8: &mut Chip8) {
+        let _ = chip8.step(&KeyBoard::new());
+    }
+
+    fn run(history: &mut History, chip8: &mut Chip8, n: usize) {
+        for _ in 0..n {
+            history.step(chip8, step);
+        }
+    }
+
+    #[test]
+    fn step_back() {
+        let mut chip8 = Chip8::new();
+        chip8.load_rom(&ROM).unwrap();
+        let mut history = History::new(7, 3);
+        assert!(!history.step_back(&mut chip8, step));
+        run(&mut history, &mut chip8, 20);
+        let states: Vec<Chip8> = (0..5)
+            .map(|_| {
+                let state = chip8.clone();
+                history.step(&mut chip8, step);
+                state
+            })
+            .collect();
+        for state in states.iter().rev() {
+            assert!(history.step_back(&mut chip8, step));
+            assert_eq!(state.cycles(), chip8.cycles());
+            assert_eq!(state.registers(), chip8.registers());
+            assert_eq!(state.memory(), chip8.memory());
+        }
+
+        // Only three snapshots are kept
+        while history.step_back(&mut chip8, step) {}
+        assert_eq!(7, chip8.cycles());
+        assert_eq!(7, history.first());
+    }
+
+    #[test]
+    fn reverse() {
+        let mut chip8 = Chip8::new();
+        chip8.load_rom(&ROM).unwrap();
+        let mut history = History::new(4, 100);
+        run(&mut history, &mut chip8, 23);
+        // The store runs at cycles 3, 8, 13, ...
+        let at_store = |m: &Chip8| m.registers().pc == 0x206;
+        assert!(history.reverse(&mut chip8, step, at_store));
+        assert_eq!(18, chip8.cycles());
+        assert!(history.reverse(&mut chip8, step, at_store));
+        assert_eq!(13, chip8.cycles());
+        assert!(!history.reverse(&mut chip8, step, |_| false));
+        assert_eq!(0, chip8.cycles());
+    }
+
+    #[test]
+    fn last_write() {
+        let mut chip8 = Chip8::new();
+        chip8.load_rom(&ROM).unwrap();
+        let mut history = History::new(4, 100);
+        run(&mut history, &mut chip8, 15);
+        let write = history.last_write(0x302).unwrap();
+        assert_eq!(
+            (8, 0x206, 0x302, 1),
+            (write.cycle, write.pc, write.addr, write.len)
+        );
+        assert_eq!(None, history.last_write(0x304));
+
+        // Going back forgets the writes after it
+        history.step_back(&mut chip8, step);
+        history.step_back(&mut chip8, step);
+        assert_eq!(None, history.last_write(0x303));
+        history.step(&mut chip8, step);
+        assert_eq!(13, history.last_write(0x303).unwrap().cycle);
+
+        // An edit becomes a new snapshot to go back to
+        chip8.write_memory(0x300, &[0xff]).unwrap();
+        history.edited(&chip8);
+        run(&mut history, &mut chip8, 3);
+        for _ in 0..3 {
+            assert!(history.step_back(&mut chip8, step));
+        }
+        assert_eq!(0xff, chip8.memory()[0x300]);
+        assert!(history.step_back(&mut chip8, step));
+        assert_eq!(0, chip8.memory()[0x300]);
+
+        let wrapped = MemoryWrite {
+            cycle: 0,
+            pc: 0,
+            addr: 0xffe,
+            len: 3,
+        };
+        assert!(wrapped.contains(0x000) && !wrapped.contains(0x001));
+    }
+}
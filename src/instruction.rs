@@ -0,0 +1,132 @@
+// An opcode with its operands already extracted, so that executing it
+// again does not need to mask and shift the opcode.
+// X and Y are register numbers, N is a nibble, NN a byte and NNN an address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Instruction {
+    Cls,
+    Ret,
+    Jp(u16),
+    Call(u16),
+    SeImm(u8, u8),
+    SneImm(u8, u8),
+    SeReg(u8, u8),
+    LdImm(u8, u8),
+    AddImm(u8, u8),
+    LdReg(u8, u8),
+    Or(u8, u8),
+    And(u8, u8),
+    Xor(u8, u8),
+    AddReg(u8, u8),
+    Sub(u8, u8),
+    Shr(u8, u8),
+    Subn(u8, u8),
+    Shl(u8, u8),
+    SneReg(u8, u8),
+    LdI(u16),
+    // NNN and X, which is used instead of V0 with the jumping quirk
+    JpV0(u16, u8),
+    Rnd(u8, u8),
+    Drw(u8, u8, u8),
+    Skp(u8),
+    Sknp(u8),
+    LdVxDt(u8),
+    LdVxK(u8),
+    LdDtVx(u8),
+    LdStVx(u8),
+    AddI(u8),
+    LdF(u8),
+    LdB(u8),
+    Store(u8),
+    Load(u8),
+    Invalid(u16),
+}
+
+impl Instruction {
+    // Bytes of memory from I that the instruction reads and writes
+    pub fn memory_access(&self) -> (u16, u16) {
+        use Instruction::*;
+        match *self {
+            Drw(_, _, n) => (n as u16, 0),
+            Load(x) => (x as u16 + 1, 0),
+            LdB(_) => (0, 3),
+            Store(x) => (0, x as u16 + 1),
+            _ => (0, 0),
+        }
+    }
+}
+
+pub fn decode(opcode: u16) -> Instruction {
+    use Instruction::*;
+    let x = ((opcode & 0x0F00) >> 8) as u8;
+    let y = ((opcode & 0x00F0) >> 4) as u8;
+    let n = (opcode & 0x000F) as u8;
+    let nn = (opcode & 0x00FF) as u8;
+    let nnn = opcode & 0x0FFF;
+    match opcode & 0xF000 {
+        0x0000 => match opcode {
+            0x00E0 => Cls,
+            0x00EE => Ret,
+            _ => Invalid(opcode),
+        },
+        0x1000 => Jp(nnn),
+        0x2000 => Call(nnn),
+        0x3000 => SeImm(x, nn),
+        0x4000 => SneImm(x, nn),
+        0x5000 if n == 0 => SeReg(x, y),
+        0x6000 => LdImm(x, nn),
+        0x7000 => AddImm(x, nn),
+        0x8000 => match n {
+            0x0 => LdReg(x, y),
+            0x1 => Or(x, y),
+            0x2 => And(x, y),
+            0x3 => Xor(x, y),
+            0x4 => AddReg(x, y),
+            0x5 => Sub(x, y),
+            0x6 => Shr(x, y),
+            0x7 => Subn(x, y),
+            0xE => Shl(x, y),
+            _ => Invalid(opcode),
+        },
+        0x9000 if n == 0 => SneReg(x, y),
+        0xA000 => LdI(nnn),
+        0xB000 => JpV0(nnn, x),
+        0xC000 => Rnd(x, nn),
+        0xD000 => Drw(x, y, n),
+        0xE000 => match nn {
+            0x9E => Skp(x),
+            0xA1 => Sknp(x),
+            _ => Invalid(opcode),
+        },
+        0xF000 => match nn {
+            0x07 => LdVxDt(x),
+            0x0A => LdVxK(x),
+            0x15 => LdDtVx(x),
+            0x18 => LdStVx(x),
+            0x1E => AddI(x),
+            0x29 => LdF(x),
+            0x33 => LdB(x),
+            0x55 => Store(x),
+            0x65 => Load(x),
+            _ => Invalid(opcode),
+        },
+        _ => Invalid(opcode),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disasm;
+
+    #[test]
+    fn decode_matches_disassembler() {
+        // Exactly the opcodes the disassembler does not print as data are instructions
+        for opcode in 0..=0xffff {
+            let invalid = decode(opcode) == Instruction::Invalid(opcode);
+            let data = disasm::disassemble(opcode).starts_with("DW");
+            // The disassembler prints 0NNN as SYS, which the interpreter does not run
+            let sys = opcode & 0xF000 == 0 && opcode != 0x00E0 && opcode != 0x00EE;
+            assert_eq!(data || sys, invalid, "0x{:04x}", opcode);
+        }
+    }
+}
@@ -1,30 +1,103 @@
+use sdl2::audio::{AudioCallback, AudioDevice, AudioSpecDesired};
+use sdl2::controller::{Button, GameController};
 use sdl2::event::Event;
 use sdl2::keyboard::Keycode;
-use sdl2::pixels::Color;
-use sdl2::rect::{Point, Rect};
-use sdl2::render::WindowCanvas;
-use sdl2::EventPump;
+use sdl2::pixels::{Color, PixelFormatEnum};
+use sdl2::render::{TextureCreator, WindowCanvas};
+use sdl2::video::WindowContext;
+use sdl2::{EventPump, GameControllerSubsystem};
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
 
-use crate::chip8::{Chip8, KeyBoard, GFX_SIZE_COL, GFX_SIZE_ROW};
+use rs_chip_8::chip8::{KeyBoard, GFX_SIZE, GFX_SIZE_COL, GFX_SIZE_ROW, KEY_NUM};
+use rs_chip_8::config::{Audio, LauncherOptions};
+use rs_chip_8::filter::{Filter, Image};
+use rs_chip_8::launcher::{Launcher, MenuKey};
+use rs_chip_8::osd::Osd;
+use rs_chip_8::palette::{Palette, Rgb};
+use rs_chip_8::speed::Speed;
 
-const WHITE: Color = Color::RGB(0xe0, 0xf8, 0xd0);
-const BLACK: Color = Color::RGB(0x08, 0x18, 0x20);
-const PIXEL_SIZE: u32 = 10;
+pub const PIXEL_SIZE: u32 = 10;
+pub const TITLE: &str = "rs-chip-8";
+const MAX_PIXEL_SIZE: u32 = 40;
+const VOLUME_STEP: f32 = 0.05;
+const TONE: f32 = 440.0;
+
+// Square wave played while the sound timer runs
+struct SquareWave {
+    phase_inc: f32,
+    phase: f32,
+    volume: f32,
+}
+
+impl AudioCallback for SquareWave {
+    type Channel = f32;
+
+    fn callback(&mut self, out: &mut [f32]) {
+        for x in out.iter_mut() {
+            *x = if self.phase <= 0.5 {
+                self.volume
+            } else {
+                -self.volume
+            };
+            self.phase = (self.phase + self.phase_inc) % 1.0;
+        }
+    }
+}
+
+// Front end keys that control the emulator rather than the program
+#[derive(Default)]
+pub struct Hotkeys {
+    pub pause_flag: bool,
+    // Run one frame while paused, cleared by the front end
+    pub advance_flag: bool,
+    // Restart the program, cleared by the front end
+    pub reset_flag: bool,
+    // Reload the ROM from disk and restart, cleared by the front end
+    pub reload_flag: bool,
+    // Write and read the save state, cleared by the front end
+    pub save_flag: bool,
+    pub load_flag: bool,
+}
 
 pub struct IO {
     canvas: WindowCanvas,
+    creator: TextureCreator<WindowContext>,
     event_pump: EventPump,
+    audio: Option<AudioDevice<SquareWave>>,
+    palette: Palette,
+    volume: f32,
+    pixel_size: u32,
+    keymap: HashMap<Keycode, usize>,
+    filters: Vec<Filter>,
+    filters_on: bool,
+    speed: Speed,
+    osd: Osd,
+    // Gamepads for the ROM browser, opened as they are plugged in
+    controller_subsystem: Option<GameControllerSubsystem>,
+    controllers: Vec<GameController>,
+    redraw: bool,
 }
 impl IO {
-    pub fn setup() -> IO {
+    pub fn setup(
+        pixel_size: u32,
+        palette: Palette,
+        audio: &Audio,
+        keymap: HashMap<Keycode, usize>,
+    ) -> IO {
+        let pixel_size = pixel_size.clamp(1, MAX_PIXEL_SIZE);
         let sdl_context = sdl2::init().unwrap();
         let video_subsystem = sdl_context.video().unwrap();
 
         let window = video_subsystem
             .window(
-                "rust-sdl2 demo",
-                GFX_SIZE_COL as u32 * PIXEL_SIZE,
-                GFX_SIZE_ROW as u32 * PIXEL_SIZE,
+                TITLE,
+                GFX_SIZE_COL as u32 * pixel_size,
+                GFX_SIZE_ROW as u32 * pixel_size,
             )
             .position_centered()
             .build()
@@ -33,87 +106,439 @@ impl IO {
         let mut _canvas = window.into_canvas().build().unwrap();
         let mut _event_pump = sdl_context.event_pump().unwrap();
 
-        _canvas.set_draw_color(WHITE);
+        // Run without sound if there is no audio device
+        let volume = audio.volume.clamp(0.0, 1.0);
+        let _audio = if audio.enabled {
+            open_audio(&sdl_context, volume)
+                .inspect_err(|e| println!("error audio: {}", e))
+                .ok()
+        } else {
+            None
+        };
+
+        let controller_subsystem = sdl_context
+            .game_controller()
+            .inspect_err(|e| println!("error gamepad: {}", e))
+            .ok();
+
+        _canvas.set_draw_color(color(palette.background));
         _canvas.clear();
         _canvas.present();
         IO {
+            creator: _canvas.texture_creator(),
             canvas: _canvas,
             event_pump: _event_pump,
+            audio: _audio,
+            palette,
+            volume,
+            pixel_size,
+            keymap,
+            filters: Vec::new(),
+            filters_on: true,
+            speed: Speed::new(),
+            osd: Osd::new(),
+            controller_subsystem,
+            controllers: Vec::new(),
+            redraw: false,
         }
     }
-    pub fn draw_graphics(&mut self, chip8: &Chip8) {
-        self.canvas.set_draw_color(WHITE);
-        self.canvas.clear();
-        self.canvas.set_draw_color(BLACK);
-        for y in 0..GFX_SIZE_ROW {
-            for x in 0..GFX_SIZE_COL {
-                let _x = (x * PIXEL_SIZE as usize) as i32;
-                let _y = (y * PIXEL_SIZE as usize) as i32;
-                if chip8.gfx[y * GFX_SIZE_COL + x] == 1 {
-                    self.canvas
-                        .fill_rect(Rect::new(_x, _y, PIXEL_SIZE, PIXEL_SIZE))
-                        .unwrap();
-                } else {
-                    self.canvas.draw_point(Point::new(_x, _y)).unwrap();
-                }
+    pub fn set_title(&mut self, title: &str) {
+        self.canvas.window_mut().set_title(title).unwrap();
+    }
+    pub fn speed(&mut self) -> &mut Speed {
+        &mut self.speed
+    }
+    pub fn osd(&mut self) -> &mut Osd {
+        &mut self.osd
+    }
+    pub fn palette(&self) -> Palette {
+        self.palette
+    }
+    pub fn volume(&self) -> f32 {
+        self.volume
+    }
+    pub fn pixel_size(&self) -> u32 {
+        self.pixel_size
+    }
+    // True when the screen changed without the emulator drawing
+    pub fn redraw(&mut self) -> bool {
+        let r = self.redraw | self.osd.update(Instant::now());
+        self.redraw = false;
+        r
+    }
+    pub fn play_sound(&mut self, on: bool) {
+        if let Some(audio) = &self.audio {
+            if on {
+                audio.resume();
+            } else {
+                audio.pause();
+            }
+        }
+    }
+    fn set_volume(&mut self, volume: f32) {
+        self.volume = volume.clamp(0.0, 1.0);
+        if let Some(audio) = &mut self.audio {
+            audio.lock().volume = self.volume;
+        }
+        self.osd
+            .toast(&format!("volume {:.0}%", self.volume * 100.0));
+    }
+    fn set_pixel_size(&mut self, pixel_size: u32) {
+        self.pixel_size = pixel_size.clamp(1, MAX_PIXEL_SIZE);
+        self.canvas
+            .window_mut()
+            .set_size(
+                GFX_SIZE_COL as u32 * self.pixel_size,
+                GFX_SIZE_ROW as u32 * self.pixel_size,
+            )
+            .unwrap();
+        self.osd.toast(&format!("scale {}", self.pixel_size));
+        self.redraw = true;
+    }
+    pub fn set_filters(&mut self, filters: Vec<Filter>) {
+        self.filters = filters;
+    }
+    // Renders the screen into an RGBA buffer on the CPU, applies the
+    // filters and shows it, so no GPU acceleration is needed
+    pub fn draw_graphics(&mut self, levels: &[u8; GFX_SIZE]) {
+        let scale = self.pixel_size as usize;
+        let mut image = Image::render(levels, &self.palette, scale);
+        if self.filters_on {
+            for &filter in &self.filters {
+                image.apply(filter, &self.palette, scale);
             }
         }
+        self.osd.draw(&mut image, (scale / 4).max(1));
+        self.show(&image);
+    }
+    fn show(&mut self, image: &Image) {
+        let mut texture = self
+            .creator
+            .create_texture_streaming(
+                PixelFormatEnum::RGBA32,
+                image.width as u32,
+                image.height as u32,
+            )
+            .unwrap();
+        texture
+            .update(None, &image.pixels, image.width * 4)
+            .unwrap();
+        self.canvas.copy(&texture, None, None).unwrap();
         self.canvas.present();
     }
-    pub fn set_key(&mut self, kb: &mut KeyBoard) {
-        for event in self.event_pump.poll_iter() {
+    // Shows the ROM browser until a ROM is chosen, None when the window is closed
+    pub fn launcher(
+        &mut self,
+        launcher: &mut Launcher,
+        options: &mut LauncherOptions,
+    ) -> Option<PathBuf> {
+        self.set_title(&format!("{} - choose a ROM", TITLE));
+        let scale = self.pixel_size as usize;
+        let (width, height) = (GFX_SIZE_COL * scale, GFX_SIZE_ROW * scale);
+        loop {
+            let mut image = Image::new(width, height, self.palette.background);
+            launcher.draw(&mut image, &self.palette, options, (scale / 4).max(1));
+            self.show(&image);
+            for key in self.menu_keys()? {
+                if let Some(path) = launcher.input(key, options) {
+                    self.set_title(TITLE);
+                    return Some(path);
+                }
+            }
+            thread::sleep(Duration::from_millis(16));
+        }
+    }
+    // Keyboard and gamepad input for menus, None to quit
+    fn menu_keys(&mut self) -> Option<Vec<MenuKey>> {
+        let mut keys = Vec::new();
+        let events: Vec<Event> = self.event_pump.poll_iter().collect();
+        for event in events {
+            let key = match event {
+                Event::Quit { .. }
+                | Event::KeyDown {
+                    keycode: Some(Keycode::Escape),
+                    ..
+                }
+                | Event::ControllerButtonDown {
+                    button: Button::B | Button::Back,
+                    ..
+                } => return None,
+                Event::KeyDown {
+                    keycode: Some(code),
+                    ..
+                } => match code {
+                    Keycode::Up => MenuKey::Up,
+                    Keycode::Down => MenuKey::Down,
+                    Keycode::PageUp => MenuKey::PageUp,
+                    Keycode::PageDown => MenuKey::PageDown,
+                    Keycode::Return | Keycode::KpEnter => MenuKey::Select,
+                    Keycode::F => MenuKey::Favourite,
+                    _ => continue,
+                },
+                Event::ControllerButtonDown { button, .. } => match button {
+                    Button::DPadUp => MenuKey::Up,
+                    Button::DPadDown => MenuKey::Down,
+                    Button::LeftShoulder => MenuKey::PageUp,
+                    Button::RightShoulder => MenuKey::PageDown,
+                    Button::A | Button::Start => MenuKey::Select,
+                    Button::Y => MenuKey::Favourite,
+                    _ => continue,
+                },
+                Event::ControllerDeviceAdded { which, .. } => {
+                    if let Some(subsystem) = &self.controller_subsystem {
+                        match subsystem.open(which) {
+                            Ok(controller) => self.controllers.push(controller),
+                            Err(e) => println!("error gamepad: {}", e),
+                        }
+                    }
+                    continue;
+                }
+                _ => continue,
+            };
+            keys.push(key);
+        }
+        Some(keys)
+    }
+    pub fn set_key(&mut self, kb: &mut KeyBoard, hotkeys: &mut Hotkeys) {
+        let events: Vec<Event> = self.event_pump.poll_iter().collect();
+        for event in events {
             match event {
                 Event::Quit { .. }
                 | Event::KeyDown {
                     keycode: Some(Keycode::Escape),
                     ..
                 } => kb.fin_flag = true,
+                Event::KeyDown {
+                    keycode: Some(Keycode::P),
+                    repeat: false,
+                    ..
+                } => hotkeys.pause_flag = !hotkeys.pause_flag,
+                Event::KeyDown {
+                    keycode: Some(Keycode::Period),
+                    ..
+                } => hotkeys.advance_flag = true,
+                Event::KeyDown {
+                    keycode: Some(Keycode::Backspace),
+                    repeat: false,
+                    ..
+                } => hotkeys.reset_flag = true,
+                Event::KeyDown {
+                    keycode: Some(Keycode::F8),
+                    repeat: false,
+                    ..
+                } => hotkeys.reload_flag = true,
+                Event::KeyDown {
+                    keycode: Some(Keycode::F10),
+                    repeat: false,
+                    ..
+                } => hotkeys.save_flag = true,
+                Event::KeyDown {
+                    keycode: Some(Keycode::F11),
+                    repeat: false,
+                    ..
+                } => hotkeys.load_flag = true,
+                Event::KeyDown {
+                    keycode: Some(Keycode::Minus),
+                    ..
+                } => self.speed.slower(),
+                Event::KeyDown {
+                    keycode: Some(Keycode::Equals),
+                    ..
+                } => self.speed.faster(),
+                Event::KeyDown {
+                    keycode: Some(Keycode::Tab),
+                    ..
+                } => self.speed.fast_forward = true,
+                Event::KeyUp {
+                    keycode: Some(Keycode::Tab),
+                    ..
+                } => self.speed.fast_forward = false,
+                Event::KeyDown {
+                    keycode: Some(Keycode::Backquote),
+                    ..
+                } => self.speed.slow_motion = true,
+                Event::KeyUp {
+                    keycode: Some(Keycode::Backquote),
+                    ..
+                } => self.speed.slow_motion = false,
+                Event::KeyDown {
+                    keycode: Some(Keycode::F2),
+                    repeat: false,
+                    ..
+                } => {
+                    self.palette = self.palette.next();
+                    self.osd.toast(&format!("palette {}", self.palette));
+                    self.redraw = true;
+                }
+                Event::KeyDown {
+                    keycode: Some(Keycode::F7),
+                    repeat: false,
+                    ..
+                } => {
+                    self.filters_on = !self.filters_on;
+                    let on = if self.filters_on { "on" } else { "off" };
+                    self.osd.toast(&format!("filters {}", on));
+                    self.redraw = true;
+                }
+                Event::KeyDown {
+                    keycode: Some(Keycode::F9),
+                    repeat: false,
+                    ..
+                } => {
+                    self.osd.show_stats = !self.osd.show_stats;
+                    self.redraw = true;
+                }
+                Event::KeyDown {
+                    keycode: Some(Keycode::F3),
+                    ..
+                } => self.set_volume(self.volume - VOLUME_STEP),
+                Event::KeyDown {
+                    keycode: Some(Keycode::F4),
+                    ..
+                } => self.set_volume(self.volume + VOLUME_STEP),
+                Event::KeyDown {
+                    keycode: Some(Keycode::F5),
+                    ..
+                } => self.set_pixel_size(self.pixel_size - 1),
+                Event::KeyDown {
+                    keycode: Some(Keycode::F6),
+                    ..
+                } => self.set_pixel_size(self.pixel_size + 1),
                 Event::KeyDown {
                     keycode: Some(key_code),
                     ..
-                } => match key_code {
-                    Keycode::X => kb.key[0x0] = 1,
-                    Keycode::Num1 => kb.key[0x1] = 1,
-                    Keycode::Num2 => kb.key[0x2] = 1,
-                    Keycode::Num3 => kb.key[0x3] = 1,
-                    Keycode::Q => kb.key[0x4] = 1,
-                    Keycode::W => kb.key[0x5] = 1,
-                    Keycode::E => kb.key[0x6] = 1,
-                    Keycode::A => kb.key[0x7] = 1,
-                    Keycode::S => kb.key[0x8] = 1,
-                    Keycode::D => kb.key[0x9] = 1,
-                    Keycode::Z => kb.key[0xa] = 1,
-                    Keycode::C => kb.key[0xb] = 1,
-                    Keycode::Num4 => kb.key[0xc] = 1,
-                    Keycode::R => kb.key[0xd] = 1,
-                    Keycode::F => kb.key[0xe] = 1,
-                    Keycode::V => kb.key[0xf] = 1,
-                    _ => (),
-                },
+                } => {
+                    if let Some(&k) = self.keymap.get(&key_code) {
+                        kb.key[k] = 1;
+                    }
+                }
                 Event::KeyUp {
                     keycode: Some(key_code),
                     ..
-                } => match key_code {
-                    Keycode::X => kb.key[0x0] = 0,
-                    Keycode::Num1 => kb.key[0x1] = 0,
-                    Keycode::Num2 => kb.key[0x2] = 0,
-                    Keycode::Num3 => kb.key[0x3] = 0,
-                    Keycode::Q => kb.key[0x4] = 0,
-                    Keycode::W => kb.key[0x5] = 0,
-                    Keycode::E => kb.key[0x6] = 0,
-                    Keycode::A => kb.key[0x7] = 0,
-                    Keycode::S => kb.key[0x8] = 0,
-                    Keycode::D => kb.key[0x9] = 0,
-                    Keycode::Z => kb.key[0xa] = 0,
-                    Keycode::C => kb.key[0xb] = 0,
-                    Keycode::Num4 => kb.key[0xc] = 0,
-                    Keycode::R => kb.key[0xd] = 0,
-                    Keycode::F => kb.key[0xe] = 0,
-                    Keycode::V => kb.key[0xf] = 0,
-                    _ => (),
-                },
+                } => {
+                    if let Some(&k) = self.keymap.get(&key_code) {
+                        kb.key[k] = 0;
+                    }
+                }
                 _ => (),
             }
         }
     }
 }
+
+// Keyboard key for each CHIP-8 key
+pub fn default_keymap() -> HashMap<Keycode, usize> {
+    let keys = [
+        Keycode::X,
+        Keycode::Num1,
+        Keycode::Num2,
+        Keycode::Num3,
+        Keycode::Q,
+        Keycode::W,
+        Keycode::E,
+        Keycode::A,
+        Keycode::S,
+        Keycode::D,
+        Keycode::Z,
+        Keycode::C,
+        Keycode::Num4,
+        Keycode::R,
+        Keycode::F,
+        Keycode::V,
+    ];
+    keys.into_iter().enumerate().map(|(k, c)| (c, k)).collect()
+}
+
+// One mapping per line, the CHIP-8 key in hex and the SDL key name:
+//
+//     # CHIP-8 key = keyboard key
+//     5 = Up
+//     8 = Down
+//
+// Keys that are not listed keep their default mapping.
+pub fn load_keymap(
+    keymap: &mut HashMap<Keycode, usize>,
+    filename: &Path,
+) -> Result<(), Box<dyn Error>> {
+    for (n, line) in fs::read_to_string(filename)?.lines().enumerate() {
+        let line = line.split('#').next().unwrap().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let err = || {
+            format!(
+                "{}:{}: invalid mapping: {}",
+                filename.display(),
+                n + 1,
+                line
+            )
+        };
+        let (key, name) = line.split_once('=').ok_or_else(err)?;
+        map_key(keymap, key, name).ok_or_else(err)?;
+    }
+    Ok(())
+}
+
+// The [keymap] table of the config file, in the same form as the keymap file
+pub fn apply_keymap(
+    keymap: &mut HashMap<Keycode, usize>,
+    entries: &BTreeMap<String, String>,
+) -> Result<(), String> {
+    for (key, name) in entries {
+        map_key(keymap, key, name)
+            .ok_or_else(|| format!("config: invalid mapping: {} = {}", key, name))?;
+    }
+    Ok(())
+}
+
+fn map_key(keymap: &mut HashMap<Keycode, usize>, key: &str, name: &str) -> Option<()> {
+    let key = usize::from_str_radix(key.trim(), 16).ok()?;
+    let code = Keycode::from_name(name.trim())?;
+    if key >= KEY_NUM || HOTKEYS.contains(&code) {
+        return None;
+    }
+    keymap.retain(|_, k| *k != key);
+    keymap.insert(code, key);
+    Some(())
+}
+
+// Keys the emulator itself uses
+const HOTKEYS: [Keycode; 18] = [
+    Keycode::Escape,
+    Keycode::P,
+    Keycode::F2,
+    Keycode::F3,
+    Keycode::F4,
+    Keycode::F5,
+    Keycode::F6,
+    Keycode::F7,
+    Keycode::F8,
+    Keycode::F9,
+    Keycode::F10,
+    Keycode::F11,
+    Keycode::Period,
+    Keycode::Backspace,
+    Keycode::Minus,
+    Keycode::Equals,
+    Keycode::Tab,
+    Keycode::Backquote,
+];
+
+fn open_audio(sdl_context: &sdl2::Sdl, volume: f32) -> Result<AudioDevice<SquareWave>, String> {
+    let desired = AudioSpecDesired {
+        freq: Some(44_100),
+        channels: Some(1),
+        samples: None,
+    };
+    sdl_context
+        .audio()?
+        .open_playback(None, &desired, |spec| SquareWave {
+            phase_inc: TONE / spec.freq as f32,
+            phase: 0.0,
+            volume,
+        })
+}
+
+fn color(rgb: Rgb) -> Color {
+    Color::RGB(rgb[0], rgb[1], rgb[2])
+}
@@ -0,0 +1,223 @@
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::config::LauncherOptions;
+use crate::filter::Image;
+use crate::osd::{text, text_width, LINE_HEIGHT};
+use crate::palette::Palette;
+use crate::rom::{has_extension, ROM_EXTENSIONS};
+use crate::romdb::{Database, RomInfo};
+
+const PAGE: usize = 10;
+
+// Menu input from the keyboard or a gamepad
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MenuKey {
+    Up,
+    Down,
+    PageUp,
+    PageDown,
+    Select,
+    Favourite,
+}
+
+pub struct Entry {
+    pub path: PathBuf,
+    // From the ROM database
+    pub info: Option<RomInfo>,
+}
+
+impl Entry {
+    fn new(path: PathBuf, db: &Database) -> Entry {
+        let info = fs::read(&path)
+            .ok()
+            .and_then(|rom| db.lookup(&rom).cloned());
+        Entry { path, info }
+    }
+
+    // The title from the database or the file name
+    pub fn name(&self) -> String {
+        match &self.info {
+            Some(info) => info.title.clone(),
+            None => file_name(&self.path),
+        }
+    }
+}
+
+// Lists the favourite and recent ROMs and then the ROMs in a directory
+pub struct Launcher {
+    dir: PathBuf,
+    entries: Vec<Entry>,
+    selected: usize,
+}
+
+impl Launcher {
+    pub fn new(
+        dir: &Path,
+        options: &LauncherOptions,
+        db: &Database,
+    ) -> Result<Launcher, Box<dyn Error>> {
+        let mut files: Vec<PathBuf> = fs::read_dir(dir)
+            .map_err(|e| format!("{}: {}", dir.display(), e))?
+            .filter_map(|entry| entry.ok().map(|e| e.path()))
+            .filter(|path| {
+                path.is_file()
+                    && path
+                        .to_str()
+                        .is_some_and(|p| has_extension(p, &ROM_EXTENSIONS))
+            })
+            .collect();
+        files.sort();
+
+        let mut paths: Vec<PathBuf> = Vec::new();
+        let listed = options.favourites.iter().chain(&options.recent);
+        for path in listed.filter(|p| p.is_file()).cloned().chain(files) {
+            let path = path.canonicalize().unwrap_or(path);
+            if !paths.contains(&path) {
+                paths.push(path);
+            }
+        }
+        Ok(Launcher {
+            dir: dir.to_path_buf(),
+            entries: paths.into_iter().map(|p| Entry::new(p, db)).collect(),
+            selected: 0,
+        })
+    }
+
+    pub fn selected(&self) -> Option<&Entry> {
+        self.entries.get(self.selected)
+    }
+
+    // Moves the selection or toggles the selected favourite,
+    // returns the ROM to start when one is chosen
+    pub fn input(&mut self, key: MenuKey, options: &mut LauncherOptions) -> Option<PathBuf> {
+        let last = self.entries.len().saturating_sub(1);
+        match key {
+            MenuKey::Up => self.selected = self.selected.saturating_sub(1),
+            MenuKey::Down => self.selected = (self.selected + 1).min(last),
+            MenuKey::PageUp => self.selected = self.selected.saturating_sub(PAGE),
+            MenuKey::PageDown => self.selected = (self.selected + PAGE).min(last),
+            MenuKey::Favourite => {
+                if let Some(entry) = self.selected() {
+                    options.toggle_favourite(&entry.path.clone());
+                }
+            }
+            MenuKey::Select => return self.selected().map(|e| e.path.clone()),
+        }
+        None
+    }
+
+    // The list with the selected ROM's details and the keys below it
+    pub fn draw(
+        &self,
+        image: &mut Image,
+        palette: &Palette,
+        options: &LauncherOptions,
+        size: usize,
+    ) {
+        let margin = 2 * size;
+        let line = LINE_HEIGHT * size;
+        let columns = image.width.saturating_sub(2 * margin) / (text_width("x", size) + size);
+        let rows = (image.height.saturating_sub(2 * margin) / line).saturating_sub(5);
+        let mut print = |row: usize, s: &str, level: u8| {
+            let s: String = s.chars().take(columns).collect();
+            text(
+                image,
+                margin,
+                margin + row * line,
+                &s,
+                size,
+                palette.blend(level),
+            );
+        };
+
+        print(0, &format!("roms in {}", self.dir.display()), 255);
+        if self.entries.is_empty() {
+            print(2, "none found", 160);
+        }
+        let first = self
+            .selected
+            .saturating_sub(rows / 2)
+            .min(self.entries.len().saturating_sub(rows));
+        for (n, entry) in self.entries.iter().enumerate().skip(first).take(rows) {
+            let cursor = if n == self.selected { '>' } else { ' ' };
+            let star = if options.is_favourite(&entry.path) {
+                '*'
+            } else {
+                ' '
+            };
+            let level = if n == self.selected { 255 } else { 160 };
+            print(
+                2 + n - first,
+                &format!("{}{} {}", cursor, star, entry.name()),
+                level,
+            );
+        }
+
+        if let Some(entry) = self.selected() {
+            let mut details = file_name(&entry.path);
+            if let Some(info) = &entry.info {
+                if let Some(profile) = &info.profile {
+                    details += &format!("  quirks {}", profile);
+                }
+                if let Some(ipf) = info.instructions_per_frame {
+                    details += &format!("  ipf {}", ipf);
+                }
+            }
+            print(rows + 3, &details, 160);
+        }
+        print(rows + 4, "enter start  f favourite  esc quit", 160);
+    }
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn launcher() {
+        let dir = std::env::temp_dir().join(format!("rs-chip-8-launcher-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        for name in ["b.ch8", "a.c8", "notes.txt"] {
+            fs::write(dir.join(name), [0x12, 0x00]).unwrap();
+        }
+        fs::write(dir.join("c.sc8"), [0x00, 0xe0]).unwrap();
+        let dir = dir.canonicalize().unwrap();
+        let db = Database::from_json(
+            r#"[{"title": "Clear", "roms": {"159ba69f4c40be3042fc54c7fbb2025f7e49f8e0": {}}}]"#,
+        )
+        .unwrap();
+
+        let mut options = LauncherOptions::default();
+        options.favourites.push(dir.join("c.sc8"));
+        options.recent.push(dir.join("b.ch8"));
+        options.recent.push(dir.join("gone.ch8"));
+        let mut launcher = Launcher::new(&dir, &options, &db).unwrap();
+        let names: Vec<String> = launcher.entries.iter().map(|e| e.name()).collect();
+        assert_eq!(vec!["Clear", "b.ch8", "a.c8"], names);
+
+        assert_eq!(None, launcher.input(MenuKey::Up, &mut options));
+        assert_eq!(None, launcher.input(MenuKey::PageDown, &mut options));
+        assert_eq!("a.c8", launcher.selected().unwrap().name());
+        launcher.input(MenuKey::Favourite, &mut options);
+        assert!(options.is_favourite(&dir.join("a.c8")));
+        launcher.input(MenuKey::Up, &mut options);
+        assert_eq!(
+            Some(dir.join("b.ch8")),
+            launcher.input(MenuKey::Select, &mut options)
+        );
+
+        let palette = Palette::default();
+        let mut image = Image::new(640, 320, palette.background);
+        launcher.draw(&mut image, &palette, &options, 2);
+        assert!(image.pixels.chunks(4).any(|p| p[..3] == palette.foreground));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,22 @@
+pub mod asm;
+pub mod bench;
+pub mod breakpoint;
+pub mod chip8;
+pub mod config;
+pub mod conformance;
+pub mod disasm;
+pub mod display;
+pub mod filter;
+pub mod framebuffer;
+pub mod gdb;
+pub mod history;
+pub mod instruction;
+pub mod launcher;
+pub mod osd;
+pub mod palette;
+pub mod rom;
+pub mod romdb;
+pub mod speed;
+pub mod stress;
+pub mod symbols;
+pub mod trace;
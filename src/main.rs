@@ -1,44 +1,496 @@
-use chip8::{Chip8, KeyBoard};
-use io::IO;
+use clap::{Args, Parser, Subcommand};
+use io::{Hotkeys, IO};
+use rs_chip_8::chip8::recompiler::Recompiler;
+use rs_chip_8::chip8::{Chip8, KeyBoard, Quirks, State, QUIRK_PROFILES};
+use rs_chip_8::config::{Audio, Config, Settings};
+use rs_chip_8::display::{Display, Persistence, PERSISTENCE_MODES};
+use rs_chip_8::filter::{Filter, FILTERS};
+use rs_chip_8::launcher::Launcher;
+use rs_chip_8::palette::Palette;
+use rs_chip_8::romdb::{self, Database};
+use rs_chip_8::symbols::Symbols;
+use rs_chip_8::trace::{self, Tracer, TRACE_FORMATS};
+use rs_chip_8::{asm, bench, conformance, disasm, gdb, rom, stress};
 use std::env;
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::BufWriter;
+use std::net::TcpListener;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::process;
 use std::thread;
 use std::time::{Duration, Instant};
 
-mod chip8;
 mod io;
 
+const INSTRUCTIONS_PER_FRAME: usize = 10;
+
+#[derive(Parser)]
+#[command(version, about = "CHIP-8 emulator")]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Run a ROM
+    Run(RunArgs),
+    /// Print the disassembly of a ROM
+    Disasm {
+        /// ROM file, "-" for stdin, or a .zip archive
+        rom: String,
+        #[arg(long, default_value = "0x200", value_parser = parse_address)]
+        load_address: u16,
+    },
+    /// Assemble a source file into a ROM
+    Asm {
+        source: PathBuf,
+        #[arg(short, long)]
+        output: PathBuf,
+        #[arg(long, default_value = "0x200", value_parser = parse_address)]
+        load_address: u16,
+        /// Also write the address of every label to a symbol file
+        #[arg(long)]
+        symbols: Option<PathBuf>,
+    },
+    /// Print the size, SHA-1 and database entry of a ROM
+    Info {
+        /// ROM file, "-" for stdin, or a .zip archive
+        rom: String,
+    },
+    /// Check the test ROMs in a directory against their golden images
+    Test {
+        #[arg(default_value = "tests/roms")]
+        dir: PathBuf,
+        /// Rewrite the golden images from the actual screens
+        #[arg(long)]
+        bless: bool,
+    },
+    /// Measure how fast the interpreter runs a ROM, without frame limiting
+    Bench {
+        /// ROM file, "-" for stdin, or a .zip archive
+        rom: String,
+        #[arg(long, default_value_t = 10_000_000)]
+        instructions: u64,
+        /// Measure the recompiler instead of the interpreter
+        #[arg(long)]
+        recompile: bool,
+    },
+    /// Wait for a GDB connection on a local port and debug a ROM with it
+    Gdb {
+        /// ROM file, "-" for stdin, or a .zip archive
+        rom: String,
+        #[arg(long, default_value_t = 1234)]
+        port: u16,
+        /// Quirk profile: default, chip8 or schip
+        #[arg(long, value_parser = parse_profile)]
+        quirks: Option<Quirks>,
+        /// Instructions per 60 Hz timer tick
+        #[arg(long, default_value_t = INSTRUCTIONS_PER_FRAME)]
+        ipf: usize,
+        #[arg(long, default_value = "0x200", value_parser = parse_address)]
+        load_address: u16,
+        /// Labels for the call stack, by default the ROM's .sym file if there is one
+        #[arg(long)]
+        symbols: Option<PathBuf>,
+    },
+    /// Print a binary trace as text
+    Trace { file: PathBuf },
+    /// Run random ROMs and report the first one that panics
+    Stress {
+        #[arg(default_value_t = 10_000)]
+        count: usize,
+    },
+}
+
+#[derive(Args)]
+struct RunArgs {
+    /// ROM file, "-" for stdin, or a .zip archive. Without one a ROM browser opens
+    rom: Option<String>,
+    /// Instructions per frame, at 60 frames per second
+    #[arg(long)]
+    ipf: Option<usize>,
+    /// Quirk profile: default, chip8 or schip
+    #[arg(long, value_parser = parse_profile)]
+    quirks: Option<Quirks>,
+    /// Window pixels per CHIP-8 pixel
+    #[arg(long)]
+    scale: Option<u32>,
+    /// Palette name (gameboy, classic, amber, green, octo) or "#background,#foreground"
+    #[arg(long, value_parser = parse_palette)]
+    palette: Option<Palette>,
+    /// Seed for the random number generator
+    #[arg(long)]
+    seed: Option<u64>,
+    /// Run without a window for FRAMES frames and print the screen
+    #[arg(long, value_name = "FRAMES")]
+    headless: Option<usize>,
+    /// Key mapping file
+    #[arg(long)]
+    keymap: Option<PathBuf>,
+    /// Start paused, P toggles pause
+    #[arg(long)]
+    paused: bool,
+    /// Address the ROM is loaded at and starts from
+    #[arg(long, default_value = "0x200", value_parser = parse_address)]
+    load_address: u16,
+    /// Show pixels from earlier frames: off, antiflicker or phosphor
+    #[arg(long)]
+    persistence: Option<String>,
+    /// Run compiled basic blocks instead of interpreting each instruction
+    #[arg(long)]
+    recompile: bool,
+    /// Reload the ROM into a reset machine whenever the file changes
+    #[arg(long)]
+    watch: bool,
+    /// With --watch, keep the registers, screen and memory outside the program
+    #[arg(long, requires = "watch")]
+    keep_state: bool,
+    /// Log each executed instruction to a file
+    #[arg(long, conflicts_with = "recompile")]
+    trace: Option<PathBuf>,
+    /// Trace format: text or binary
+    #[arg(long, default_value = "text", requires = "trace")]
+    trace_format: String,
+    /// Only trace instructions in an address range such as 0x200-0x2ff
+    #[arg(long, requires = "trace", value_parser = parse_range)]
+    trace_range: Option<RangeInclusive<u16>>,
+    /// Keep only the last N instructions and write them when the program fails
+    #[arg(long, value_name = "N", requires = "trace")]
+    trace_last: Option<usize>,
+}
+
 fn main() {
-    // check arg
-    let args: Vec<String> = env::args().collect();
-    if args.len() != 2 {
-        println!("invalid argumnts");
-        return;
+    let cli = Cli::parse();
+    let result = match cli.command {
+        Command::Run(args) => run(args),
+        Command::Disasm { rom, load_address } => {
+            rom::read(&rom).map(|rom| print!("{}", disasm::listing(&rom, load_address)))
+        }
+        Command::Asm {
+            source,
+            output,
+            load_address,
+            symbols,
+        } => assemble(&source, &output, load_address, symbols.as_deref()),
+        Command::Info { rom } => info(&rom),
+        Command::Test { dir, bless } => test(&dir, bless),
+        Command::Bench {
+            rom,
+            instructions,
+            recompile,
+        } => bench(&rom, instructions, recompile),
+        Command::Gdb {
+            rom,
+            port,
+            quirks,
+            ipf,
+            load_address,
+            symbols,
+        } => debug(&rom, port, quirks, ipf, load_address, symbols),
+        Command::Trace { file } => fs::read(&file)
+            .map_err(|e| format!("{}: {}", file.display(), e).into())
+            .and_then(|bytes| trace::read_binary(&bytes).map_err(|e| e.into()))
+            .map(|entries| entries.iter().for_each(|e| println!("{}", e.text()))),
+        Command::Stress { count } => {
+            match stress::random_roms(count, 10_000, rand::random()) {
+                Ok(()) => println!("{} ROMs ok", count),
+                Err(rom) => println!("panicked on {:02x?}", rom),
+            }
+            Ok(())
+        }
+    };
+    if let Err(e) = result {
+        println!("error {}", e);
+        process::exit(1);
     }
+}
 
-    // Set up render system and resiger input callbacks
-    let mut io = IO::setup();
-    let mut key_board = KeyBoard::new();
+fn run(args: RunArgs) -> Result<(), Box<dyn Error>> {
+    let mut config = Config::load()?;
+    let filename = match &args.rom {
+        Some(filename) => filename.clone(),
+        None if args.headless.is_some() => return Err("--headless needs a ROM".into()),
+        None => match browse(&mut config)? {
+            Some(filename) => filename,
+            None => return Ok(()),
+        },
+    };
+    let rom = rom::read(&filename)?;
 
-    // Initialize the Chip8 system and load the game into the memory
+    // Initialize the Chip8 system and load the game into the memory. Options are
+    // taken from the config file, then the ROM database, then the ROM's section
+    // of the config file and finally the command line
     let mut my_chip8 = Chip8::new();
-    if let Err(e) = my_chip8.load_game(&args[1]) {
-        println!("error {}", e);
-        return;
+    my_chip8.set_load_address(args.load_address)?;
+    let mut instructions_per_frame = INSTRUCTIONS_PER_FRAME;
+    let mut palette = Palette::default();
+    let mut scale = io::PIXEL_SIZE;
+    apply_settings(
+        &config.settings,
+        &mut my_chip8,
+        &mut instructions_per_frame,
+        &mut palette,
+        &mut scale,
+    )?;
+    if let Some(info) = Database::load().lookup(&rom) {
+        println!("{}", info.title);
+        if let Some(quirks) = info.quirks() {
+            my_chip8.set_quirks(quirks);
+        }
+        if let Some(n) = info.instructions_per_frame {
+            instructions_per_frame = n;
+        }
+        if let Some(p) = info.palette {
+            palette = p;
+        }
+        for (name, key) in &info.keys {
+            println!("{}: {:X}", name, key);
+        }
+    }
+    apply_settings(
+        &config.rom_settings(&filename, &romdb::sha1(&rom)),
+        &mut my_chip8,
+        &mut instructions_per_frame,
+        &mut palette,
+        &mut scale,
+    )?;
+    if let Some(quirks) = args.quirks {
+        my_chip8.set_quirks(quirks);
+    }
+    if let Some(n) = args.ipf {
+        instructions_per_frame = n;
+    }
+    if let Some(p) = args.palette {
+        palette = p;
+    }
+    if let Some(n) = args.scale {
+        scale = n;
+    }
+    if let Some(seed) = args.seed {
+        my_chip8.set_seed(seed);
+    }
+    my_chip8.load_rom(&rom)?;
+
+    let mut recompiler = args.recompile.then(Recompiler::new);
+    let mut tracer = args
+        .trace
+        .as_ref()
+        .map(|path| tracer(path, &args))
+        .transpose()?;
+    let mut run_frame =
+        |chip8: &mut Chip8, recompiler: &mut Option<Recompiler>, kb: &KeyBoard| match (
+            recompiler,
+            &mut tracer,
+        ) {
+            (Some(r), _) => r.run_frame(chip8, kb, instructions_per_frame),
+            (None, Some(t)) => t.run_frame(chip8, kb, instructions_per_frame),
+            (None, None) => chip8.run_frame(kb, instructions_per_frame),
+        };
+
+    if let Some(frames) = args.headless {
+        let kb = KeyBoard::new();
+        for _ in 0..frames {
+            if run_frame(&mut my_chip8, &mut recompiler, &kb)? != State::Running {
+                break;
+            }
+        }
+        print!("{}", conformance::render(&my_chip8.gfx));
+        return Ok(());
     }
+
+    // Remember the ROM for the browser
+    if filename != "-" {
+        let path = Path::new(&filename);
+        if config
+            .launcher
+            .add_recent(&path.canonicalize().unwrap_or(path.into()))
+        {
+            config.save()?;
+        }
+    }
+
+    // Set up render system and resiger input callbacks
+    let mut keymap = io::default_keymap();
+    io::apply_keymap(&mut keymap, &config.keymap)?;
+    if let Some(path) = &args.keymap {
+        io::load_keymap(&mut keymap, path)?;
+    }
+    let options = &config.display;
+    let mode = args.persistence.as_ref().unwrap_or(&options.persistence);
+    let persistence =
+        Persistence::from_name(mode, options.frames, options.decay).ok_or_else(|| {
+            format!(
+                "invalid persistence {}, expected one of {}",
+                mode,
+                PERSISTENCE_MODES.join(", ")
+            )
+        })?;
+    let mut display = Display::new(persistence);
+    let filters = options
+        .filters
+        .iter()
+        .map(|name| {
+            Filter::from_name(name).ok_or_else(|| {
+                let names: Vec<&str> = FILTERS.iter().map(|(n, _)| *n).collect();
+                format!(
+                    "invalid filter {}, expected one of {}",
+                    name,
+                    names.join(", ")
+                )
+            })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut io = IO::setup(scale, palette, &config.audio, keymap);
+    io.set_filters(filters);
+    let mut key_board = KeyBoard::new();
+    let mut hotkeys = Hotkeys {
+        pause_flag: args.paused,
+        ..Hotkeys::default()
+    };
+
     // my_chip8.dump();
     let d = Duration::from_nanos(1_000_000_000 / 60);
+    let mut state = State::Running;
+    let mut title = String::new();
+    let state_path = state_path(&config, &filename);
+    let mut watch = args.watch.then(|| rom::Watch::new(&filename)).transpose()?;
+    let mut ticks: u64 = 0;
+    // Emulated frames counted for the stats
+    let (mut fps_frames, mut fps_since) = (0, Instant::now());
     loop {
         let s = Instant::now();
 
-        // Emulate one cycle
-        my_chip8.emulate_cycle(&key_board);
+        // Look at the file twice a second
+        ticks += 1;
+        if ticks.is_multiple_of(30) && watch.as_mut().is_some_and(|w| w.changed()) {
+            if args.keep_state {
+                let result = rom::read(&filename)
+                    .and_then(|rom| my_chip8.load_rom(&rom).map_err(|e| e.into()));
+                match result {
+                    Ok(()) => {
+                        state = State::Running;
+                        title.clear();
+                        io.osd().toast("reloaded");
+                    }
+                    Err(e) => {
+                        eprintln!("{}: {}", filename, e);
+                        io.osd().toast("reload failed");
+                    }
+                }
+            } else {
+                hotkeys.reload_flag = true;
+            }
+        }
 
-        // If the draw flag is set, update the screen
-        if my_chip8.draw_flag() {
-            io.draw_graphics(&my_chip8);
+        let reset = hotkeys.reset_flag | hotkeys.reload_flag;
+        if hotkeys.reload_flag {
+            // Keeps running the old ROM if the file can't be loaded
+            match reload(&mut my_chip8, &filename) {
+                Ok(()) => io.osd().toast("reloaded"),
+                Err(e) => {
+                    eprintln!("{}: {}", filename, e);
+                    io.osd().toast("reload failed");
+                }
+            }
+            recompiler = args.recompile.then(Recompiler::new);
+        } else if reset {
+            my_chip8.reset();
+            io.osd().toast("reset");
+        }
+        if reset {
+            hotkeys.reset_flag = false;
+            hotkeys.reload_flag = false;
+            state = State::Running;
+            title.clear();
         }
 
-        io.set_key(&mut key_board);
+        if hotkeys.save_flag {
+            match save_state(&my_chip8, state_path.as_deref()) {
+                Ok(()) => io.osd().toast("state saved"),
+                Err(e) => {
+                    eprintln!("save state: {}", e);
+                    io.osd().toast("save failed");
+                }
+            }
+            hotkeys.save_flag = false;
+        }
+        let loaded = hotkeys.load_flag;
+        if loaded {
+            match load_state(&mut my_chip8, state_path.as_deref()) {
+                Ok(()) => {
+                    recompiler = args.recompile.then(Recompiler::new);
+                    state = State::Running;
+                    title.clear();
+                    io.osd().toast("state loaded");
+                }
+                Err(e) => {
+                    eprintln!("load state: {}", e);
+                    io.osd().toast("load failed");
+                }
+            }
+            hotkeys.load_flag = false;
+        }
+
+        // Paused runs only the frames asked for with the advance key
+        let paused = hotkeys.pause_flag;
+        let frames = if paused {
+            hotkeys.advance_flag as usize
+        } else {
+            io.speed().frames()
+        };
+        hotkeys.advance_flag = false;
+
+        // Emulate the frames due at the current speed
+        let mut drawn = reset | loaded;
+        for _ in 0..frames {
+            if state == State::Running {
+                state = run_frame(&mut my_chip8, &mut recompiler, &key_board)
+                    .inspect_err(|_| my_chip8.dump())?;
+                if let State::Trapped(_) = state {
+                    my_chip8.dump();
+                }
+                drawn |= my_chip8.draw_flag();
+                fps_frames += 1;
+            }
+            // Frames keep fading after the program stops
+            display.push(&my_chip8.gfx);
+        }
+
+        if fps_since.elapsed() >= Duration::from_secs(1) {
+            let profile = my_chip8.quirks().profile().unwrap_or("custom");
+            let stats = format!(
+                "{} fps  ipf {}  {}",
+                fps_frames, instructions_per_frame, profile
+            );
+            io.osd().set_stats(stats);
+            (fps_frames, fps_since) = (0, Instant::now());
+        }
+
+        // Shown in the window title and on the screen
+        let status = match state {
+            State::Running if paused => Some("paused".to_string()),
+            State::Running => io.speed().label(),
+            State::Halted => Some("program finished".to_string()),
+            State::Trapped(addr) => Some(format!("memory trap at 0x{:x}", addr)),
+        };
+        let status_title = status.as_deref().unwrap_or(io::TITLE);
+        if status_title != title {
+            io.set_title(status_title);
+            title = status_title.to_string();
+            io.osd().set_status(status);
+        }
+
+        io.play_sound(state == State::Running && !paused && my_chip8.sound());
+
+        // If the draw flag is set or old frames are fading, update the screen
+        if drawn | io.redraw() | display.fading() {
+            io.draw_graphics(&display.levels());
+        }
+
+        io.set_key(&mut key_board, &mut hotkeys);
         if key_board.fin_flag {
             break;
         }
@@ -47,7 +499,252 @@ fn main() {
         if prog < d {
             thread::sleep(d - prog); // 60 Hz
         } else {
-            println!("{:?}", prog);
+            println!("frame overrun {:?}", prog);
+        }
+    }
+
+    // Keep the settings changed with the hotkeys for the next run
+    let mut changed = false;
+    if io.palette() != palette {
+        config.settings.palette = Some(io.palette().to_string());
+        changed = true;
+    }
+    if io.pixel_size() != scale {
+        config.settings.scale = Some(io.pixel_size());
+        changed = true;
+    }
+    if io.volume() != config.audio.volume {
+        config.audio.volume = io.volume();
+        changed = true;
+    }
+    if changed {
+        config.save()?;
+    }
+    Ok(())
+}
+
+// Opens the ROM browser and returns the chosen ROM,
+// None when the window is closed
+fn browse(config: &mut Config) -> Result<Option<String>, Box<dyn Error>> {
+    let dir = match &config.launcher.dir {
+        Some(dir) => dir.clone(),
+        None => env::current_dir()?,
+    };
+    let mut launcher = Launcher::new(&dir, &config.launcher, &Database::load())?;
+    let palette = match &config.settings.palette {
+        Some(name) => parse_palette(name).map_err(|e| format!("config palette: {}", e))?,
+        None => Palette::default(),
+    };
+    let scale = config.settings.scale.unwrap_or(io::PIXEL_SIZE);
+    let audio = Audio {
+        enabled: false,
+        ..config.audio.clone()
+    };
+    let mut io = IO::setup(scale, palette, &audio, io::default_keymap());
+    let options = config.launcher.clone();
+    let chosen = io.launcher(&mut launcher, &mut config.launcher);
+    if config.launcher != options {
+        config.save()?;
+    }
+    Ok(chosen.map(|path| path.to_string_lossy().into_owned()))
+}
+
+// Hard reset: clears memory and loads the ROM from disk again
+fn reload(chip8: &mut Chip8, filename: &str) -> Result<(), Box<dyn Error>> {
+    let rom = rom::read(filename)?;
+    let mut fresh = chip8.clone();
+    fresh.reset();
+    fresh.clear_memory();
+    fresh.load_rom(&rom)?;
+    *chip8 = fresh;
+    Ok(())
+}
+
+// <save dir>/<rom name>.state
+fn state_path(config: &Config, filename: &str) -> Option<PathBuf> {
+    let name = Path::new(filename).file_stem()?;
+    Some(config.save_dir()?.join(name).with_extension("state"))
+}
+
+fn save_state(chip8: &Chip8, path: Option<&Path>) -> Result<(), Box<dyn Error>> {
+    let path = path.ok_or("no save directory")?;
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(path, chip8.save_state()).map_err(|e| format!("{}: {}", path.display(), e))?;
+    Ok(())
+}
+
+fn load_state(chip8: &mut Chip8, path: Option<&Path>) -> Result<(), Box<dyn Error>> {
+    let path = path.ok_or("no save directory")?;
+    let state = fs::read(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    chip8.load_state(&state)?;
+    Ok(())
+}
+
+// Overrides the options that are set in a section of the config file
+fn apply_settings(
+    settings: &Settings,
+    chip8: &mut Chip8,
+    instructions_per_frame: &mut usize,
+    palette: &mut Palette,
+    scale: &mut u32,
+) -> Result<(), String> {
+    if let Some(profile) = &settings.quirks {
+        chip8.set_quirks(parse_profile(profile).map_err(|e| format!("config quirks: {}", e))?);
+    }
+    if let Some(n) = settings.ipf {
+        *instructions_per_frame = n;
+    }
+    if let Some(p) = &settings.palette {
+        *palette = parse_palette(p).map_err(|e| format!("config: {}", e))?;
+    }
+    if let Some(n) = settings.scale {
+        *scale = n;
+    }
+    Ok(())
+}
+
+fn assemble(
+    source: &Path,
+    output: &Path,
+    load_address: u16,
+    symbols: Option<&Path>,
+) -> Result<(), Box<dyn Error>> {
+    let program = asm::assemble(&fs::read_to_string(source)?, load_address)?;
+    fs::write(output, &program.bytes)?;
+    if let Some(path) = symbols {
+        fs::write(path, Symbols::from_labels(&program.labels).to_text())?;
+    }
+    Ok(())
+}
+
+fn info(filename: &str) -> Result<(), Box<dyn Error>> {
+    let rom = rom::read(filename)?;
+    println!("size: {} bytes", rom.len());
+    println!("sha1: {}", romdb::sha1(&rom));
+    if let Some(info) = Database::load().lookup(&rom) {
+        println!("title: {}", info.title);
+        if let Some(profile) = &info.profile {
+            println!("quirks: {}", profile);
         }
+        if let Some(n) = info.instructions_per_frame {
+            println!("instructions per frame: {}", n);
+        }
+        if let Some(p) = info.palette {
+            println!("palette: {:02x?} {:02x?}", p.background, p.foreground);
+        }
+        for (name, key) in &info.keys {
+            println!("key {}: {:X}", name, key);
+        }
+    }
+    Ok(())
+}
+
+fn test(dir: &Path, bless: bool) -> Result<(), Box<dyn Error>> {
+    let reports = conformance::run_suite(dir, bless)?;
+    let failed = reports.iter().filter(|r| !r.passed).count();
+    for r in &reports {
+        let result = if r.passed { "pass" } else { "FAIL" };
+        println!("{} [{}]: {}", r.rom, r.profile, result);
+    }
+    println!("{} passed, {} failed", reports.len() - failed, failed);
+    if failed > 0 {
+        process::exit(1);
     }
+    Ok(())
+}
+
+fn bench(filename: &str, instructions: u64, recompile: bool) -> Result<(), Box<dyn Error>> {
+    let rom = rom::read(filename)?;
+    let elapsed = bench::throughput(&rom, instructions, recompile)?;
+    println!(
+        "{} instructions in {:?}, {:.0} instructions per second",
+        instructions,
+        elapsed,
+        instructions as f64 / elapsed.as_secs_f64()
+    );
+
+    // Per class timings come from a second run that reads the clock around every instruction
+    let classes = bench::profile(&rom, instructions)?;
+    println!("class  instructions  ns/instruction");
+    for (name, class) in bench::CLASSES.iter().zip(classes) {
+        if class.count > 0 {
+            println!(
+                "{}  {:>12}  {:>14.1}",
+                name,
+                class.count,
+                class.nanos_per_instruction()
+            );
+        }
+    }
+    Ok(())
+}
+
+fn debug(
+    filename: &str,
+    port: u16,
+    quirks: Option<Quirks>,
+    ipf: usize,
+    load_address: u16,
+    symbols: Option<PathBuf>,
+) -> Result<(), Box<dyn Error>> {
+    let default = Path::new(filename).with_extension("sym");
+    let symbols = symbols.or_else(|| Some(default).filter(|p| p.is_file()));
+    let symbols = match symbols {
+        Some(path) => {
+            let text =
+                fs::read_to_string(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+            Symbols::parse(&text).map_err(|e| format!("{}: {}", path.display(), e))?
+        }
+        None => Symbols::default(),
+    };
+    let mut chip8 = Chip8::new();
+    chip8.set_load_address(load_address)?;
+    if let Some(quirks) = quirks {
+        chip8.set_quirks(quirks);
+    }
+    chip8.load_rom(&rom::read(filename)?)?;
+    let listener = TcpListener::bind(("127.0.0.1", port))?;
+    println!("waiting for gdb on {}", listener.local_addr()?);
+    gdb::serve(&listener, &mut chip8, filename, ipf, symbols)
+}
+
+fn tracer(path: &Path, args: &RunArgs) -> Result<Tracer, Box<dyn Error>> {
+    let format = trace::Format::from_name(&args.trace_format).ok_or_else(|| {
+        format!(
+            "invalid trace format {}, expected one of {}",
+            args.trace_format,
+            TRACE_FORMATS.join(", ")
+        )
+    })?;
+    let file = File::create(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    Ok(Tracer::new(
+        Box::new(BufWriter::new(file)),
+        format,
+        args.trace_range.clone().unwrap_or(0..=0xfff),
+        args.trace_last,
+    ))
+}
+
+fn parse_range(s: &str) -> Result<RangeInclusive<u16>, String> {
+    trace::parse_range(s).ok_or_else(|| format!("invalid address range: {}", s))
+}
+
+// Accepts 0x-prefixed hex or decimal addresses
+fn parse_address(s: &str) -> Result<u16, String> {
+    let addr = asm::number(s).ok_or_else(|| format!("invalid address: {}", s))?;
+    if addr < 0x1000 {
+        Ok(addr)
+    } else {
+        Err(format!("address out of memory: {}", s))
+    }
+}
+
+fn parse_profile(s: &str) -> Result<Quirks, String> {
+    Quirks::from_profile(s).ok_or_else(|| format!("expected one of {}", QUIRK_PROFILES.join(", ")))
+}
+
+fn parse_palette(s: &str) -> Result<Palette, String> {
+    Palette::parse(s).ok_or_else(|| format!("invalid palette: {}", s))
 }
@@ -0,0 +1,260 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use crate::filter::Image;
+use crate::palette::Rgb;
+
+// Characters 0x20 to 0x5F, 4x5 pixels in the high nibble of each byte
+// like the CHIP-8 font, whose digits and A-F are the same.
+// Lower case letters are drawn in upper case.
+const FONT: [u8; 64 * 5] = [
+    0x00, 0x00, 0x00, 0x00, 0x00, // space
+    0x40, 0x40, 0x40, 0x00, 0x40, // !
+    0xA0, 0xA0, 0x00, 0x00, 0x00, // "
+    0xA0, 0xF0, 0xA0, 0xF0, 0xA0, // #
+    0x70, 0xC0, 0x60, 0x30, 0xE0, // $
+    0x90, 0x20, 0x40, 0x80, 0x90, // %
+    0x40, 0xA0, 0x40, 0xA0, 0x50, // &
+    0x40, 0x40, 0x00, 0x00, 0x00, // '
+    0x20, 0x40, 0x40, 0x40, 0x20, // (
+    0x40, 0x20, 0x20, 0x20, 0x40, // )
+    0x00, 0xA0, 0x40, 0xA0, 0x00, // *
+    0x00, 0x40, 0xE0, 0x40, 0x00, // +
+    0x00, 0x00, 0x00, 0x40, 0x80, // ,
+    0x00, 0x00, 0xE0, 0x00, 0x00, // -
+    0x00, 0x00, 0x00, 0x00, 0x40, // .
+    0x10, 0x20, 0x40, 0x80, 0x80, // /
+    0xF0, 0x90, 0x90, 0x90, 0xF0, // 0
+    0x20, 0x60, 0x20, 0x20, 0x70, // 1
+    0xF0, 0x10, 0xF0, 0x80, 0xF0, // 2
+    0xF0, 0x10, 0xF0, 0x10, 0xF0, // 3
+    0x90, 0x90, 0xF0, 0x10, 0x10, // 4
+    0xF0, 0x80, 0xF0, 0x10, 0xF0, // 5
+    0xF0, 0x80, 0xF0, 0x90, 0xF0, // 6
+    0xF0, 0x10, 0x20, 0x40, 0x40, // 7
+    0xF0, 0x90, 0xF0, 0x90, 0xF0, // 8
+    0xF0, 0x90, 0xF0, 0x10, 0xF0, // 9
+    0x00, 0x40, 0x00, 0x40, 0x00, // :
+    0x00, 0x40, 0x00, 0x40, 0x80, // ;
+    0x20, 0x40, 0x80, 0x40, 0x20, // <
+    0x00, 0xE0, 0x00, 0xE0, 0x00, // =
+    0x80, 0x40, 0x20, 0x40, 0x80, // >
+    0xE0, 0x10, 0x60, 0x00, 0x40, // ?
+    0x60, 0x90, 0xB0, 0x80, 0x70, // @
+    0xF0, 0x90, 0xF0, 0x90, 0x90, // A
+    0xE0, 0x90, 0xE0, 0x90, 0xE0, // B
+    0xF0, 0x80, 0x80, 0x80, 0xF0, // C
+    0xE0, 0x90, 0x90, 0x90, 0xE0, // D
+    0xF0, 0x80, 0xF0, 0x80, 0xF0, // E
+    0xF0, 0x80, 0xF0, 0x80, 0x80, // F
+    0xF0, 0x80, 0xB0, 0x90, 0xF0, // G
+    0x90, 0x90, 0xF0, 0x90, 0x90, // H
+    0x70, 0x20, 0x20, 0x20, 0x70, // I
+    0x10, 0x10, 0x10, 0x90, 0x60, // J
+    0x90, 0xA0, 0xC0, 0xA0, 0x90, // K
+    0x80, 0x80, 0x80, 0x80, 0xF0, // L
+    0x90, 0xF0, 0xF0, 0x90, 0x90, // M
+    0x90, 0xD0, 0xB0, 0x90, 0x90, // N
+    0x60, 0x90, 0x90, 0x90, 0x60, // O
+    0xE0, 0x90, 0xE0, 0x80, 0x80, // P
+    0x60, 0x90, 0x90, 0xA0, 0x50, // Q
+    0xE0, 0x90, 0xE0, 0xA0, 0x90, // R
+    0x70, 0x80, 0x60, 0x10, 0xE0, // S
+    0xF0, 0x40, 0x40, 0x40, 0x40, // T
+    0x90, 0x90, 0x90, 0x90, 0x60, // U
+    0x90, 0x90, 0x90, 0x60, 0x60, // V
+    0x90, 0x90, 0xF0, 0xF0, 0x90, // W
+    0x90, 0x60, 0x60, 0x60, 0x90, // X
+    0x90, 0x90, 0x70, 0x10, 0x60, // Y
+    0xF0, 0x20, 0x40, 0x80, 0xF0, // Z
+    0x60, 0x40, 0x40, 0x40, 0x60, // [
+    0x80, 0x80, 0x40, 0x20, 0x10, // backslash
+    0x60, 0x20, 0x20, 0x20, 0x60, // ]
+    0x40, 0xA0, 0x00, 0x00, 0x00, // ^
+    0x00, 0x00, 0x00, 0x00, 0xF0, // _
+];
+const GLYPH_WIDTH: usize = 4;
+const GLYPH_HEIGHT: usize = 5;
+const TEXT: Rgb = [0xff, 0xff, 0xff];
+const TOAST_TIME: Duration = Duration::from_secs(2);
+const MAX_TOASTS: usize = 4;
+
+// Text drawn over the game: short-lived messages, a status such as
+// "paused" and optional stats
+pub struct Osd {
+    // Newest last, with the time each one goes away
+    toasts: VecDeque<(String, Instant)>,
+    status: Option<String>,
+    stats: Option<String>,
+    pub show_stats: bool,
+    // Something shown in the last frame is gone
+    removed: bool,
+}
+
+impl Osd {
+    pub fn new() -> Osd {
+        Osd {
+            toasts: VecDeque::new(),
+            status: None,
+            stats: None,
+            show_stats: false,
+            removed: false,
+        }
+    }
+
+    pub fn toast(&mut self, text: &str) {
+        if self.toasts.len() == MAX_TOASTS {
+            self.toasts.pop_front();
+        }
+        self.toasts
+            .push_back((text.to_string(), Instant::now() + TOAST_TIME));
+    }
+
+    // Shown until changed, None for nothing
+    pub fn set_status(&mut self, status: Option<String>) {
+        if status.is_none() && self.status.is_some() {
+            self.removed = true;
+        }
+        self.status = status;
+    }
+
+    pub fn set_stats(&mut self, stats: String) {
+        self.stats = Some(stats);
+    }
+
+    // Drops the messages shown long enough and returns true if the
+    // overlay has to be drawn, or erased
+    pub fn update(&mut self, now: Instant) -> bool {
+        let before = self.toasts.len();
+        self.toasts.retain(|(_, until)| *until > now);
+        let removed = std::mem::take(&mut self.removed) || self.toasts.len() != before;
+        removed || !self.toasts.is_empty() || self.status.is_some() || self.stats()
+    }
+
+    fn stats(&self) -> bool {
+        self.show_stats && self.stats.is_some()
+    }
+
+    // Stats top left, status top right, messages bottom left
+    pub fn draw(&self, image: &mut Image, size: usize) {
+        let margin = 2 * size;
+        let line = LINE_HEIGHT * size;
+        if let (true, Some(stats)) = (self.show_stats, &self.stats) {
+            label(image, margin, margin, stats, size);
+        }
+        if let Some(status) = &self.status {
+            let x = image
+                .width
+                .saturating_sub(margin + text_width(status, size));
+            label(image, x, margin, status, size);
+        }
+        let bottom = image.height.saturating_sub(margin);
+        for (n, (toast, _)) in self.toasts.iter().rev().enumerate() {
+            if let Some(y) = bottom.checked_sub((n + 1) * line) {
+                label(image, margin, y, toast, size);
+            }
+        }
+    }
+}
+
+impl Default for Osd {
+    fn default() -> Osd {
+        Osd::new()
+    }
+}
+
+pub(crate) const LINE_HEIGHT: usize = GLYPH_HEIGHT + 2;
+
+pub(crate) fn text_width(s: &str, size: usize) -> usize {
+    (s.chars().count() * (GLYPH_WIDTH + 1)).saturating_sub(1) * size
+}
+
+// Draws a line of text on a darkened box
+fn label(image: &mut Image, x: usize, y: usize, s: &str, size: usize) {
+    let (width, height) = (text_width(s, size), GLYPH_HEIGHT * size);
+    for py in y.saturating_sub(size)..(y + height + size).min(image.height) {
+        for px in x.saturating_sub(size)..(x + width + size).min(image.width) {
+            image.dim(px, py, 0.3);
+        }
+    }
+    text(image, x, y, s, size, TEXT);
+}
+
+// Draws a line of text clipped to the image, `size` is the width of
+// a font pixel in image pixels
+pub(crate) fn text(image: &mut Image, x: usize, y: usize, s: &str, size: usize, rgb: Rgb) {
+    for (n, c) in s.chars().enumerate() {
+        let glyph = glyph(c);
+        let gx = x + n * (GLYPH_WIDTH + 1) * size;
+        for (row, bits) in glyph.iter().enumerate() {
+            for col in 0..GLYPH_WIDTH {
+                if bits & (0x80 >> col) == 0 {
+                    continue;
+                }
+                for dy in 0..size {
+                    for dx in 0..size {
+                        let (px, py) = (gx + col * size + dx, y + row * size + dy);
+                        if px < image.width && py < image.height {
+                            image.set_pixel(px, py, rgb);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn glyph(c: char) -> &'static [u8] {
+    let c = match c.to_ascii_uppercase() {
+        c @ ' '..='_' => c,
+        _ => '?',
+    };
+    let k = (c as usize - 0x20) * GLYPH_HEIGHT;
+    &FONT[k..k + GLYPH_HEIGHT]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chip8::{CHIP8_FONTSET, GFX_SIZE};
+    use crate::palette::Palette;
+
+    #[test]
+    fn font() {
+        assert_eq!(CHIP8_FONTSET[..50], FONT[16 * 5..26 * 5]);
+        assert_eq!(CHIP8_FONTSET[50..], FONT[33 * 5..39 * 5]);
+        assert_eq!(glyph('A'), glyph('a'));
+        assert_eq!(glyph('?'), glyph('\u{e9}'));
+    }
+
+    #[test]
+    fn toasts() {
+        let palette = Palette::parse("#000000,#000000").unwrap();
+        let blank = Image::render(&[0; GFX_SIZE], &palette, 4);
+        let mut osd = Osd::new();
+        let now = Instant::now();
+        assert!(!osd.update(now));
+
+        osd.toast("speed 200%");
+        assert!(osd.update(now));
+        let mut image = Image::render(&[0; GFX_SIZE], &palette, 4);
+        osd.draw(&mut image, 1);
+        // The top of the 'S' in the bottom left corner
+        let y = image.height - 2 - 7;
+        assert_eq!([0; 3], image.pixel(2, y));
+        assert_eq!(TEXT, image.pixel(3, y));
+
+        // Drawn once more to erase the message after it is gone
+        let later = now + 2 * TOAST_TIME;
+        assert!(osd.update(later));
+        assert!(!osd.update(later));
+        let mut image = Image::render(&[0; GFX_SIZE], &palette, 4);
+        osd.draw(&mut image, 1);
+        assert!(blank.pixels == image.pixels);
+
+        osd.set_stats("60 fps".to_string());
+        assert!(!osd.update(later));
+        osd.show_stats = true;
+        assert!(osd.update(later));
+    }
+}
@@ -0,0 +1,155 @@
+use std::fmt;
+
+pub type Rgb = [u8; 3];
+
+// Background and foreground
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Palette {
+    pub background: Rgb,
+    pub foreground: Rgb,
+}
+
+pub const PALETTES: [(&str, Palette); 5] = [
+    (
+        "gameboy",
+        Palette {
+            background: [0xe0, 0xf8, 0xd0],
+            foreground: [0x08, 0x18, 0x20],
+        },
+    ),
+    (
+        "classic",
+        Palette {
+            background: [0x00, 0x00, 0x00],
+            foreground: [0xff, 0xff, 0xff],
+        },
+    ),
+    (
+        "amber",
+        Palette {
+            background: [0x1a, 0x10, 0x00],
+            foreground: [0xff, 0xb0, 0x00],
+        },
+    ),
+    (
+        "green",
+        Palette {
+            background: [0x00, 0x14, 0x00],
+            foreground: [0x33, 0xff, 0x33],
+        },
+    ),
+    (
+        "octo",
+        Palette {
+            background: [0x99, 0x66, 0x00],
+            foreground: [0xff, 0xcc, 0x00],
+        },
+    ),
+];
+
+impl Default for Palette {
+    fn default() -> Palette {
+        PALETTES[0].1
+    }
+}
+
+impl Palette {
+    // A palette name or "#background,#foreground"
+    pub fn parse(s: &str) -> Option<Palette> {
+        if let Some((_, p)) = PALETTES.iter().find(|(name, _)| *name == s) {
+            return Some(*p);
+        }
+        let (bg, fg) = s.split_once(',')?;
+        Some(Palette {
+            background: parse_color(bg.trim())?,
+            foreground: parse_color(fg.trim())?,
+        })
+    }
+
+    // The colour `level` / 255 of the way from the background to the foreground
+    pub fn blend(&self, level: u8) -> Rgb {
+        let mut rgb = self.background;
+        for (c, (&bg, &fg)) in rgb
+            .iter_mut()
+            .zip(self.background.iter().zip(&self.foreground))
+        {
+            *c = ((bg as u32 * (255 - level as u32) + fg as u32 * level as u32) / 255) as u8;
+        }
+        rgb
+    }
+
+    // The built-in palette after this one, custom palettes go back to the first
+    pub fn next(&self) -> Palette {
+        let k = PALETTES.iter().position(|(_, p)| p == self);
+        PALETTES[k.map_or(0, |k| (k + 1) % PALETTES.len())].1
+    }
+}
+
+// The name of a built-in palette, otherwise the form `parse` accepts
+impl fmt::Display for Palette {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match PALETTES.iter().find(|(_, p)| p == self) {
+            Some((name, _)) => write!(f, "{}", name),
+            None => {
+                let [br, bg, bb] = self.background;
+                let [fr, fg, fb] = self.foreground;
+                write!(
+                    f,
+                    "#{:02x}{:02x}{:02x},#{:02x}{:02x}{:02x}",
+                    br, bg, bb, fr, fg, fb
+                )
+            }
+        }
+    }
+}
+
+// "#rrggbb"
+pub fn parse_color(s: &str) -> Option<Rgb> {
+    let hex = s.strip_prefix('#')?;
+    if hex.len() != 6 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    let n = u32::from_str_radix(hex, 16).ok()?;
+    Some([(n >> 16) as u8, (n >> 8) as u8, n as u8])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        assert_eq!(Some([0x12, 0x34, 0x56]), parse_color("#123456"));
+        assert_eq!(None, parse_color("123456"));
+        assert_eq!(None, parse_color("#+12345"));
+        assert_eq!(Some(PALETTES[1].1), Palette::parse("classic"));
+        assert_eq!(
+            Some(Palette {
+                background: [0, 0, 0],
+                foreground: [0xff, 0xcc, 0],
+            }),
+            Palette::parse("#000000, #ffcc00")
+        );
+        assert_eq!(None, Palette::parse("#000000"));
+    }
+
+    #[test]
+    fn display() {
+        for (name, p) in PALETTES {
+            assert_eq!(name, p.to_string());
+        }
+        let p = Palette::parse("#000000,#ffcc00").unwrap();
+        assert_eq!("#000000,#ffcc00", p.to_string());
+        assert_eq!(Some(p), Palette::parse(&p.to_string()));
+        assert_eq!(PALETTES[0].1, p.next());
+        assert_eq!(PALETTES[0].1, PALETTES[4].1.next());
+    }
+
+    #[test]
+    fn blend() {
+        let p = Palette::parse("#000000,#ff8040").unwrap();
+        assert_eq!(p.background, p.blend(0));
+        assert_eq!(p.foreground, p.blend(255));
+        assert_eq!([0x80, 0x40, 0x20], p.blend(128));
+    }
+}
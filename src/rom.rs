@@ -0,0 +1,123 @@
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{self, Read, Seek};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use zip::ZipArchive;
+
+pub const ROM_EXTENSIONS: [&str; 4] = ["ch8", "c8", "sc8", "xo8"];
+
+// Reads a ROM from a file, from stdin when the path is "-",
+// or from the first ROM inside a .zip archive
+pub fn read(path: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+    if path == "-" {
+        let mut rom = Vec::new();
+        io::stdin().read_to_end(&mut rom)?;
+        return Ok(rom);
+    }
+    if has_extension(path, &["zip"]) {
+        return read_zip(File::open(path)?);
+    }
+    Ok(fs::read(path)?)
+}
+
+pub fn read_zip<R: Read + Seek>(reader: R) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut archive = ZipArchive::new(reader)?;
+    let mut names: Vec<String> = archive
+        .file_names()
+        .filter(|name| has_extension(name, &ROM_EXTENSIONS))
+        .map(|name| name.to_string())
+        .collect();
+    names.sort();
+    let name = names.first().ok_or("no ROM found in the archive")?;
+    let mut rom = Vec::new();
+    archive.by_name(name)?.read_to_end(&mut rom)?;
+    Ok(rom)
+}
+
+pub fn has_extension(path: &str, extensions: &[&str]) -> bool {
+    Path::new(path)
+        .extension()
+        .and_then(|e| e.to_str())
+        .is_some_and(|e| extensions.iter().any(|x| e.eq_ignore_ascii_case(x)))
+}
+
+// Polls the modification time of a ROM file to notice rebuilds
+pub struct Watch {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+}
+
+impl Watch {
+    pub fn new(path: &str) -> Result<Watch, String> {
+        if path == "-" {
+            return Err("can't watch stdin".to_string());
+        }
+        let path = PathBuf::from(path);
+        let modified = modified(&path);
+        Ok(Watch { path, modified })
+    }
+
+    // True once for every change since the last call
+    pub fn changed(&mut self) -> bool {
+        let modified = modified(&self.path);
+        if modified.is_some() && modified != self.modified {
+            self.modified = modified;
+            return true;
+        }
+        false
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Cursor, Write};
+    use zip::write::FileOptions;
+    use zip::ZipWriter;
+
+    #[test]
+    fn zip() {
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        zip.start_file("README.txt", FileOptions::default())
+            .unwrap();
+        zip.write_all(b"readme").unwrap();
+        zip.start_file("games/PONG.CH8", FileOptions::default())
+            .unwrap();
+        zip.write_all(&[0x12, 0x00]).unwrap();
+        let archive = zip.finish().unwrap();
+
+        assert_eq!(vec![0x12, 0x00], read_zip(archive).unwrap());
+
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        zip.start_file("README.txt", FileOptions::default())
+            .unwrap();
+        let archive = zip.finish().unwrap();
+        assert!(read_zip(archive).is_err());
+    }
+
+    #[test]
+    fn watch() {
+        let path = std::env::temp_dir().join(format!("rs-chip-8-watch-{}.ch8", std::process::id()));
+        let name = path.to_str().unwrap();
+        let mut watch = Watch::new(name).unwrap();
+        assert!(!watch.changed());
+
+        fs::write(&path, [0x12, 0x00]).unwrap();
+        assert!(watch.changed());
+        assert!(!watch.changed());
+
+        let file = File::options().write(true).open(&path).unwrap();
+        let later = SystemTime::now() + std::time::Duration::from_secs(10);
+        file.set_modified(later).unwrap();
+        assert!(watch.changed());
+        fs::remove_file(&path).unwrap();
+        assert!(!watch.changed());
+        assert!(Watch::new("-").is_err());
+    }
+}
@@ -0,0 +1,190 @@
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+
+use crate::chip8::Quirks;
+use crate::config::config_dir;
+use crate::palette::{parse_color, Palette};
+
+// Entries of the community chip-8-database programs.json
+#[derive(Deserialize)]
+struct Program {
+    title: String,
+    #[serde(default)]
+    roms: HashMap<String, Rom>,
+}
+
+#[derive(Deserialize)]
+struct Rom {
+    #[serde(default)]
+    platforms: Vec<String>,
+    tickrate: Option<usize>,
+    colors: Option<Colors>,
+    #[serde(default)]
+    keys: BTreeMap<String, u8>,
+}
+
+#[derive(Deserialize)]
+struct Colors {
+    #[serde(default)]
+    pixels: Vec<String>,
+}
+
+// What the emulator needs to know to run a ROM
+#[derive(Debug, Clone, PartialEq)]
+pub struct RomInfo {
+    pub title: String,
+    pub profile: Option<String>,
+    pub instructions_per_frame: Option<usize>,
+    pub palette: Option<Palette>,
+    // Key hints, e.g. "up" -> 0x5
+    pub keys: BTreeMap<String, u8>,
+}
+
+impl RomInfo {
+    pub fn quirks(&self) -> Option<Quirks> {
+        self.profile.as_deref().and_then(Quirks::from_profile)
+    }
+}
+
+#[derive(Default)]
+pub struct Database {
+    // Keyed by the lower case SHA-1 of the ROM
+    roms: HashMap<String, RomInfo>,
+}
+
+impl Database {
+    pub fn new() -> Database {
+        Database::default()
+    }
+
+    pub fn from_json(json: &str) -> Result<Database, Box<dyn Error>> {
+        let programs: Vec<Program> = serde_json::from_str(json)?;
+        let mut db = Database::new();
+        for program in programs {
+            for (hash, rom) in program.roms {
+                let info = RomInfo {
+                    title: program.title.clone(),
+                    profile: rom.platforms.iter().find_map(|p| platform_profile(p)),
+                    instructions_per_frame: rom.tickrate,
+                    palette: rom.colors.and_then(|c| palette(&c.pixels)),
+                    keys: rom.keys,
+                };
+                db.roms.insert(hash.to_lowercase(), info);
+            }
+        }
+        Ok(db)
+    }
+
+    // Reads programs.json (e.g. the community database) and then the
+    // user's own roms.json from the config directory, missing files are skipped
+    pub fn load() -> Database {
+        let mut db = Database::new();
+        if let Some(dir) = config_dir() {
+            for name in ["programs.json", "roms.json"] {
+                match Database::read(&dir.join(name)) {
+                    Ok(Some(other)) => db.merge(other),
+                    Ok(None) => (),
+                    Err(e) => println!("error {}: {}", name, e),
+                }
+            }
+        }
+        db
+    }
+
+    fn read(path: &Path) -> Result<Option<Database>, Box<dyn Error>> {
+        if !path.exists() {
+            return Ok(None);
+        }
+        Ok(Some(Database::from_json(&fs::read_to_string(path)?)?))
+    }
+
+    // Entries of `other` replace entries with the same hash
+    pub fn merge(&mut self, other: Database) {
+        self.roms.extend(other.roms);
+    }
+
+    pub fn lookup(&self, rom: &[u8]) -> Option<&RomInfo> {
+        self.roms.get(&sha1(rom))
+    }
+}
+
+pub fn sha1(rom: &[u8]) -> String {
+    sha1_smol::Sha1::from(rom).digest().to_string()
+}
+
+fn platform_profile(platform: &str) -> Option<String> {
+    let profile = match platform {
+        "originalChip8" | "hybridVIP" => "chip8",
+        "modernChip8" => "default",
+        "superchip1" | "superchip" => "schip",
+        _ => return None,
+    };
+    Some(profile.to_string())
+}
+
+fn palette(pixels: &[String]) -> Option<Palette> {
+    match pixels {
+        [bg, fg, ..] => Some(Palette {
+            background: parse_color(bg)?,
+            foreground: parse_color(fg)?,
+        }),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const JSON: &str = r##"[
+        {
+            "title": "Pong",
+            "roms": {
+                "A9993E364706816ABA3E25717850C26C9CD0D89D": {
+                    "file": "pong.ch8",
+                    "platforms": ["xochip", "originalChip8"],
+                    "tickrate": 7,
+                    "colors": { "pixels": ["#000000", "#ffcc00"] },
+                    "keys": { "up": 1, "down": 4 }
+                }
+            }
+        },
+        { "title": "Empty" }
+    ]"##;
+
+    #[test]
+    fn lookup() {
+        let db = Database::from_json(JSON).unwrap();
+        let info = db.lookup(b"abc").unwrap();
+        assert_eq!("Pong", info.title);
+        assert_eq!(Some("chip8".to_string()), info.profile);
+        assert_eq!(Quirks::from_profile("chip8"), info.quirks());
+        assert_eq!(Some(7), info.instructions_per_frame);
+        assert_eq!(
+            Some(Palette {
+                background: [0, 0, 0],
+                foreground: [0xff, 0xcc, 0],
+            }),
+            info.palette
+        );
+        assert_eq!(Some(&4), info.keys.get("down"));
+        assert!(db.lookup(b"abd").is_none());
+    }
+
+    #[test]
+    fn merge() {
+        let mut db = Database::from_json(JSON).unwrap();
+        let user = r#"[{
+            "title": "My Pong",
+            "roms": { "a9993e364706816aba3e25717850c26c9cd0d89d": { "tickrate": 20 } }
+        }]"#;
+        db.merge(Database::from_json(user).unwrap());
+        let info = db.lookup(b"abc").unwrap();
+        assert_eq!("My Pong", info.title);
+        assert_eq!(Some(20), info.instructions_per_frame);
+        assert_eq!(None, info.palette);
+    }
+}
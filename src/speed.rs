@@ -0,0 +1,102 @@
+// Emulation speed relative to 60 frames per second
+pub const SPEEDS: [f32; 8] = [0.25, 0.5, 0.75, 1.0, 1.25, 1.5, 2.0, 3.0];
+const NORMAL: usize = 3;
+pub const FAST_FORWARD: f32 = 4.0;
+pub const SLOW_MOTION: f32 = 0.25;
+
+// Decides how many emulated frames to run in each 60 Hz host frame.
+// Fractions carry over, so half speed runs a frame every other tick.
+#[derive(Debug, Clone)]
+pub struct Speed {
+    step: usize,
+    // Held down by the user, fast-forward wins over slow motion
+    pub fast_forward: bool,
+    pub slow_motion: bool,
+    credit: f32,
+}
+
+impl Speed {
+    pub fn new() -> Speed {
+        Speed {
+            step: NORMAL,
+            fast_forward: false,
+            slow_motion: false,
+            credit: 0.0,
+        }
+    }
+
+    pub fn faster(&mut self) {
+        self.step = (self.step + 1).min(SPEEDS.len() - 1);
+    }
+
+    pub fn slower(&mut self) {
+        self.step = self.step.saturating_sub(1);
+    }
+
+    pub fn factor(&self) -> f32 {
+        if self.fast_forward {
+            FAST_FORWARD
+        } else if self.slow_motion {
+            SLOW_MOTION
+        } else {
+            SPEEDS[self.step]
+        }
+    }
+
+    // Frames to emulate in this host frame
+    pub fn frames(&mut self) -> usize {
+        self.credit += self.factor();
+        let n = self.credit as usize;
+        self.credit -= n as f32;
+        n
+    }
+
+    // Shown to the user when not at normal speed
+    pub fn label(&self) -> Option<String> {
+        if self.fast_forward {
+            Some("fast-forward".to_string())
+        } else if self.slow_motion {
+            Some("slow motion".to_string())
+        } else if self.step != NORMAL {
+            Some(format!("speed {}%", (SPEEDS[self.step] * 100.0) as u32))
+        } else {
+            None
+        }
+    }
+}
+
+impl Default for Speed {
+    fn default() -> Speed {
+        Speed::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frames(speed: &mut Speed, ticks: usize) -> usize {
+        (0..ticks).map(|_| speed.frames()).sum()
+    }
+
+    #[test]
+    fn speed() {
+        let mut speed = Speed::new();
+        assert_eq!(60, frames(&mut speed, 60));
+        assert_eq!(None, speed.label());
+
+        speed.slower();
+        assert_eq!(45, frames(&mut speed, 60));
+        assert_eq!(Some("speed 75%".to_string()), speed.label());
+        for _ in 0..SPEEDS.len() {
+            speed.faster();
+        }
+        assert_eq!(180, frames(&mut speed, 60));
+
+        speed.slow_motion = true;
+        assert_eq!(15, frames(&mut speed, 60));
+        speed.fast_forward = true;
+        assert_eq!(240, frames(&mut speed, 60));
+        assert_eq!(Some("fast-forward".to_string()), speed.label());
+    }
+}
@@ -0,0 +1,130 @@
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::panic;
+
+use crate::chip8::{Chip8, KeyBoard, MemoryPolicy, Quirks, State, KEY_NUM, QUIRK_PROFILES};
+
+// Runs the data as a ROM until it stops, fails or `cycles` run out.
+// The first two bytes are the pressed keys, one bit per key.
+pub fn run_rom(
+    data: &[u8],
+    cycles: usize,
+    policy: MemoryPolicy,
+    quirks: Quirks,
+) -> Result<State, String> {
+    let (kb, rom) = split_keys(data);
+    let mut chip8 = Chip8::new();
+    chip8.set_memory_policy(policy);
+    chip8.set_quirks(quirks);
+    chip8.load_rom(rom).map_err(|e| e.to_string())?;
+    let mut state = State::Running;
+    for _ in 0..cycles {
+        state = chip8.emulate_cycle(&kb)?;
+        if state != State::Running {
+            break;
+        }
+    }
+    Ok(state)
+}
+
+// Feeds every two bytes of the data to the decoder as an opcode,
+// regardless of where the program counter points.
+pub fn run_opcodes(data: &[u8], quirks: Quirks) -> Result<(), String> {
+    let (kb, opcodes) = split_keys(data);
+    let mut chip8 = Chip8::new();
+    chip8.set_quirks(quirks);
+    for op in opcodes.chunks_exact(2) {
+        chip8.decode_execute(u16::from_be_bytes([op[0], op[1]]), &kb)?;
+    }
+    Ok(())
+}
+
+// The first byte picks the quirk profile, for fuzzing
+pub fn split_quirks(data: &[u8]) -> (Quirks, &[u8]) {
+    match data.split_first() {
+        Some((&n, rest)) => {
+            let profile = QUIRK_PROFILES[n as usize % QUIRK_PROFILES.len()];
+            (Quirks::from_profile(profile).unwrap(), rest)
+        }
+        None => (Quirks::default(), data),
+    }
+}
+
+fn split_keys(data: &[u8]) -> (KeyBoard, &[u8]) {
+    let mut kb = KeyBoard::new();
+    if data.len() < 2 {
+        return (kb, data);
+    }
+    let keys = u16::from_be_bytes([data[0], data[1]]);
+    for k in 0..KEY_NUM {
+        kb.key[k] = ((keys >> k) & 1) as u8;
+    }
+    (kb, &data[2..])
+}
+
+// Runs `count` random ROMs and returns the first one that panics
+pub fn random_roms(count: usize, cycles: usize, seed: u64) -> Result<(), Vec<u8>> {
+    let mut rng = StdRng::seed_from_u64(seed);
+    for _ in 0..count {
+        let len = rng.gen_range(2..=512);
+        let data: Vec<u8> = (0..len).map(|_| rng.gen()).collect();
+        let ok = panic::catch_unwind(|| {
+            for profile in QUIRK_PROFILES {
+                let quirks = Quirks::from_profile(profile).unwrap();
+                for policy in [MemoryPolicy::Wrap, MemoryPolicy::Error, MemoryPolicy::Trap] {
+                    let _ = run_rom(&data, cycles, policy, quirks);
+                }
+                let _ = run_opcodes(&data, quirks);
+            }
+        })
+        .is_ok();
+        if !ok {
+            return Err(data);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn machine_errors() {
+        let policy = MemoryPolicy::Error;
+        let quirks = Quirks::default();
+        // 0x2200: calls itself until the stack overflows
+        assert!(run_rom(&[0, 0, 0x22, 0x00], 100, policy, quirks).is_err());
+        // 0x00EE: returns with an empty stack
+        assert!(run_rom(&[0, 0, 0x00, 0xee], 100, policy, quirks).is_err());
+        // 0xAFFF, 0xFF55: writes past the end of memory
+        assert!(run_rom(&[0, 0, 0xaf, 0xff, 0xff, 0x55], 100, policy, quirks).is_err());
+        // 0xAFFF, 0xD00F: reads a sprite past the end of memory
+        assert!(run_rom(&[0, 0, 0xaf, 0xff, 0xd0, 0x0f], 100, policy, quirks).is_err());
+        // 0x60FF, 0xE09E: checks a key that does not exist
+        assert!(run_rom(&[0, 0, 0x60, 0xff, 0xe0, 0x9e], 100, policy, quirks).is_err());
+        // 0x60FF, 0xF01E, 0x1202: adds to I until it overflows
+        assert_eq!(
+            Ok(State::Running),
+            run_rom(
+                &[0, 0, 0x60, 0xff, 0xf0, 0x1e, 0x12, 0x02],
+                1000,
+                policy,
+                quirks
+            )
+        );
+    }
+
+    #[test]
+    fn quirks() {
+        let chip8 = Quirks::from_profile("chip8").unwrap();
+        assert_eq!((chip8, &[0x12][..]), split_quirks(&[1, 0x12]));
+        assert_eq!((Quirks::default(), &[][..]), split_quirks(&[3]));
+        assert_eq!((Quirks::default(), &[][..]), split_quirks(&[]));
+    }
+
+    #[test]
+    fn random() {
+        assert_eq!(Ok(()), random_roms(200, 1000, 0));
+    }
+}
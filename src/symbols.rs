@@ -0,0 +1,83 @@
+use std::collections::BTreeMap;
+
+use crate::asm;
+
+// Labels of a program by address, as written by `asm --symbols`:
+//
+//     0x200 start
+//     0x20a draw_player     ; comments start with ';'
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Symbols {
+    labels: BTreeMap<u16, String>,
+}
+
+impl Symbols {
+    pub fn from_labels(labels: &BTreeMap<String, u16>) -> Symbols {
+        Symbols {
+            labels: labels
+                .iter()
+                .map(|(name, &addr)| (addr, name.clone()))
+                .collect(),
+        }
+    }
+
+    pub fn parse(text: &str) -> Result<Symbols, String> {
+        let mut labels = BTreeMap::new();
+        for (n, line) in text.lines().enumerate() {
+            let line = line.split(';').next().unwrap_or_default();
+            let words: Vec<&str> = line.split_whitespace().collect();
+            match words[..] {
+                [] => (),
+                [addr, name] => {
+                    let addr = asm::number(addr)
+                        .ok_or_else(|| format!("line {}: invalid address: {}", n + 1, addr))?;
+                    labels.insert(addr, name.to_string());
+                }
+                _ => return Err(format!("line {}: expected ADDRESS NAME", n + 1)),
+            }
+        }
+        Ok(Symbols { labels })
+    }
+
+    pub fn to_text(&self) -> String {
+        self.labels
+            .iter()
+            .map(|(addr, name)| format!("0x{:03x} {}\n", addr, name))
+            .collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.labels.is_empty()
+    }
+
+    // The address as the nearest label at or before it, e.g. draw+0x4
+    pub fn name(&self, addr: u16) -> Option<String> {
+        let (&label, name) = self.labels.range(..=addr).next_back()?;
+        Some(match addr - label {
+            0 => name.clone(),
+            offset => format!("{}+0x{:x}", name, offset),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn symbols() {
+        let program = asm::assemble("start: CALL draw\nJP start\ndraw: CLS\nRET", 0x200).unwrap();
+        let symbols = Symbols::from_labels(&program.labels);
+        assert_eq!("0x200 start\n0x204 draw\n", symbols.to_text());
+        assert_eq!(symbols, Symbols::parse(&symbols.to_text()).unwrap());
+
+        assert_eq!(None, symbols.name(0x1fe));
+        assert_eq!(Some("start".to_string()), symbols.name(0x200));
+        assert_eq!(Some("draw+0x2".to_string()), symbols.name(0x206));
+
+        let parsed = Symbols::parse("; comment\n\n0x300 data ; sprites\n").unwrap();
+        assert_eq!(Some("data".to_string()), parsed.name(0x300));
+        assert!(Symbols::parse("0x300").is_err());
+        assert!(Symbols::parse("data 0x300").is_err());
+    }
+}
@@ -0,0 +1,352 @@
+use std::collections::VecDeque;
+use std::fmt::Write as _;
+use std::io::{self, Write};
+use std::ops::RangeInclusive;
+
+use crate::chip8::{Chip8, KeyBoard, Registers, State};
+use crate::disasm::disassemble;
+
+pub const TRACE_FORMATS: [&str; 2] = ["text", "binary"];
+
+// Start of a binary trace, followed by one record per instruction:
+// cycle (u64), pc (u16), opcode (u16), number of changed registers (u8)
+// and for each change the register number (u8) and new value (u16),
+// all little endian
+const MAGIC: &[u8; 4] = b"C8T1";
+
+// Registers that can change, by number in binary traces. The PC is left
+// out because nearly every instruction changes it.
+const REGISTERS: [&str; 20] = [
+    "v0", "v1", "v2", "v3", "v4", "v5", "v6", "v7", "v8", "v9", "va", "vb", "vc", "vd", "ve", "vf",
+    "i", "sp", "dt", "st",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Text,
+    Binary,
+}
+
+impl Format {
+    pub fn from_name(name: &str) -> Option<Format> {
+        match name {
+            "text" => Some(Format::Text),
+            "binary" => Some(Format::Binary),
+            _ => None,
+        }
+    }
+}
+
+// One executed instruction
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub cycle: u64,
+    pub pc: u16,
+    pub opcode: u16,
+    // Register number and new value
+    pub changes: Vec<(u8, u16)>,
+}
+
+impl Entry {
+    fn new(cycle: u64, pc: u16, opcode: u16, before: &Registers, after: &Registers) -> Entry {
+        let (a, b) = (values(before), values(after));
+        let changes = (0..REGISTERS.len())
+            .filter(|&n| a[n] != b[n])
+            .map(|n| (n as u8, b[n]))
+            .collect();
+        Entry {
+            cycle,
+            pc,
+            opcode,
+            changes,
+        }
+    }
+
+    //      12  200  6012  LD V0, 0x12       v0=12
+    pub fn text(&self) -> String {
+        let mut line = format!(
+            "{:>8}  {:03x}  {:04x}  {:<18}",
+            self.cycle,
+            self.pc,
+            self.opcode,
+            disassemble(self.opcode)
+        );
+        for &(n, value) in &self.changes {
+            let _ = write!(line, " {}={:x}", REGISTERS[n as usize], value);
+        }
+        line.trim_end().to_string()
+    }
+
+    fn binary(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.cycle.to_le_bytes());
+        out.extend_from_slice(&self.pc.to_le_bytes());
+        out.extend_from_slice(&self.opcode.to_le_bytes());
+        out.push(self.changes.len() as u8);
+        for &(n, value) in &self.changes {
+            out.push(n);
+            out.extend_from_slice(&value.to_le_bytes());
+        }
+    }
+}
+
+fn values(r: &Registers) -> [u16; 20] {
+    let mut values = [0; 20];
+    for (value, &v) in values.iter_mut().zip(&r.v) {
+        *value = v as u16;
+    }
+    values[16] = r.i;
+    values[17] = r.sp;
+    values[18] = r.delay_timer as u16;
+    values[19] = r.sound_timer as u16;
+    values
+}
+
+// Reads back a binary trace
+pub fn read_binary(mut bytes: &[u8]) -> Result<Vec<Entry>, String> {
+    if !bytes.starts_with(MAGIC) {
+        return Err("not a binary trace".to_string());
+    }
+    bytes = &bytes[MAGIC.len()..];
+    let mut entries = Vec::new();
+    while !bytes.is_empty() {
+        let cycle = u64::from_le_bytes(take(&mut bytes)?);
+        let pc = u16::from_le_bytes(take(&mut bytes)?);
+        let opcode = u16::from_le_bytes(take(&mut bytes)?);
+        let mut changes = Vec::new();
+        for _ in 0..take::<1>(&mut bytes)?[0] {
+            let [n] = take(&mut bytes)?;
+            if n as usize >= REGISTERS.len() {
+                return Err(format!("invalid register {}", n));
+            }
+            changes.push((n, u16::from_le_bytes(take(&mut bytes)?)));
+        }
+        entries.push(Entry {
+            cycle,
+            pc,
+            opcode,
+            changes,
+        });
+    }
+    Ok(entries)
+}
+
+fn take<const N: usize>(bytes: &mut &[u8]) -> Result<[u8; N], String> {
+    let (head, rest) = bytes
+        .split_first_chunk()
+        .ok_or_else(|| "truncated trace".to_string())?;
+    *bytes = rest;
+    Ok(*head)
+}
+
+// Logs the instructions a Chip8 executes. With `last` set only the
+// last instructions are kept, and written when the program fails.
+pub struct Tracer {
+    out: Box<dyn Write>,
+    format: Format,
+    range: RangeInclusive<u16>,
+    last: Option<(VecDeque<Entry>, usize)>,
+    header: bool,
+}
+
+impl Tracer {
+    pub fn new(
+        out: Box<dyn Write>,
+        format: Format,
+        range: RangeInclusive<u16>,
+        last: Option<usize>,
+    ) -> Tracer {
+        Tracer {
+            out,
+            format,
+            range,
+            last: last.map(|n| (VecDeque::with_capacity(n), n)),
+            header: false,
+        }
+    }
+
+    // Same as `Chip8::run_frame`
+    pub fn run_frame(
+        &mut self,
+        chip8: &mut Chip8,
+        kb: &KeyBoard,
+        instructions: usize,
+    ) -> Result<State, String> {
+        let mut state = State::Running;
+        for _ in 0..instructions {
+            state = self.step(chip8, kb)?;
+            if state != State::Running {
+                break;
+            }
+        }
+        chip8.update_timers();
+        Ok(state)
+    }
+
+    // Same as `Chip8::step`
+    pub fn step(&mut self, chip8: &mut Chip8, kb: &KeyBoard) -> Result<State, String> {
+        let before = chip8.registers();
+        let cycle = chip8.cycles();
+        let pc = before.pc;
+        // Before the instruction can write over itself
+        let opcode = chip8.opcode_at(pc);
+        let result = chip8.step(kb);
+        let executed = chip8.cycles() > cycle;
+        if executed && self.range.contains(&pc) {
+            let entry = Entry::new(cycle, pc, opcode, &before, &chip8.registers());
+            self.record(entry).map_err(trace_error)?;
+        }
+        if matches!(result, Err(_) | Ok(State::Trapped(_))) {
+            self.dump().map_err(trace_error)?;
+        }
+        result
+    }
+
+    fn record(&mut self, entry: Entry) -> io::Result<()> {
+        match &mut self.last {
+            Some((entries, n)) => {
+                entries.push_back(entry);
+                if entries.len() > *n {
+                    entries.pop_front();
+                }
+                Ok(())
+            }
+            None => self.write(&entry),
+        }
+    }
+
+    fn write(&mut self, entry: &Entry) -> io::Result<()> {
+        match self.format {
+            Format::Text => writeln!(self.out, "{}", entry.text()),
+            Format::Binary => {
+                if !self.header {
+                    self.out.write_all(MAGIC)?;
+                    self.header = true;
+                }
+                let mut record = Vec::new();
+                entry.binary(&mut record);
+                self.out.write_all(&record)
+            }
+        }
+    }
+
+    // Writes out the kept instructions, if only the last ones are kept
+    pub fn dump(&mut self) -> io::Result<()> {
+        if let Some((mut entries, n)) = self.last.take() {
+            for entry in entries.drain(..) {
+                self.write(&entry)?;
+            }
+            self.last = Some((entries, n));
+        }
+        self.out.flush()
+    }
+}
+
+fn trace_error(e: io::Error) -> String {
+    format!("trace: {}", e)
+}
+
+// Parses an address range such as 0x200-0x2ff
+pub fn parse_range(s: &str) -> Option<RangeInclusive<u16>> {
+    let (lo, hi) = s.split_once('-')?;
+    let (lo, hi) = (
+        crate::asm::number(lo.trim())?,
+        crate::asm::number(hi.trim())?,
+    );
+    (lo <= hi).then_some(lo..=hi)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    // A Write that can be read after the tracer took it
+    #[derive(Clone, Default)]
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // V0 = 0x12, I = 0x345, call 0x206, then an unknown opcode
+    const ROM: [u8; 8] = [0x60, 0x12, 0xa3, 0x45, 0x22, 0x06, 0x00, 0x00];
+
+    fn trace(format: Format, range: RangeInclusive<u16>, last: Option<usize>) -> Vec<u8> {
+        let out = Shared::default();
+        let mut tracer = Tracer::new(Box::new(out.clone()), format, range, last);
+        let mut chip8 = Chip8::new();
+        chip8.load_rom(&ROM).unwrap();
+        let kb = KeyBoard::new();
+        assert!(tracer.run_frame(&mut chip8, &kb, 10).is_err());
+        let bytes = out.0.borrow().clone();
+        bytes
+    }
+
+    #[test]
+    fn text() {
+        let text = String::from_utf8(trace(Format::Text, 0..=0xfff, None)).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(4, lines.len());
+        assert!(lines[0].starts_with("       0  200  6012  LD V0, 0x12"));
+        assert!(lines[0].ends_with(" v0=12"));
+        assert!(lines[1].ends_with(" i=345"));
+        assert!(lines[2].ends_with(" sp=1"));
+        assert!(lines[3].starts_with("       3  206  0000"));
+
+        let text = String::from_utf8(trace(Format::Text, 0x202..=0x204, None)).unwrap();
+        assert_eq!(2, text.lines().count());
+    }
+
+    #[test]
+    fn binary() {
+        let entries = read_binary(&trace(Format::Binary, 0..=0xfff, None)).unwrap();
+        assert_eq!(4, entries.len());
+        assert_eq!(
+            Entry {
+                cycle: 1,
+                pc: 0x202,
+                opcode: 0xa345,
+                changes: vec![(16, 0x345)],
+            },
+            entries[1]
+        );
+        assert!(read_binary(b"C8T1\x00").is_err());
+        assert!(read_binary(b"trace").is_err());
+    }
+
+    #[test]
+    fn self_modifying() {
+        let out = Shared::default();
+        let mut tracer = Tracer::new(Box::new(out.clone()), Format::Binary, 0..=0xfff, None);
+        let mut chip8 = Chip8::new();
+        // V0 = 0x60, I = 0x204, then FX55 writes 0x60 over its own first byte
+        chip8
+            .load_rom(&[0x60, 0x60, 0xa2, 0x04, 0xf0, 0x55])
+            .unwrap();
+        let kb = KeyBoard::new();
+        for _ in 0..3 {
+            tracer.step(&mut chip8, &kb).unwrap();
+        }
+        tracer.dump().unwrap();
+        assert_eq!(0x6055, chip8.opcode_at(0x204));
+        let entries = read_binary(&out.0.borrow()).unwrap();
+        assert_eq!(0xf055, entries[2].opcode);
+    }
+
+    #[test]
+    fn last() {
+        // Only the last two instructions, written when the program fails
+        let entries = read_binary(&trace(Format::Binary, 0..=0xfff, Some(2))).unwrap();
+        let pcs: Vec<u16> = entries.iter().map(|e| e.pc).collect();
+        assert_eq!(vec![0x204, 0x206], pcs);
+        assert_eq!(Some(0x200..=0x2ff), parse_range("0x200-0x2ff"));
+        assert_eq!(None, parse_range("0x300-0x200"));
+    }
+}